@@ -0,0 +1,608 @@
+use std::collections::HashMap;
+
+use crate::checker::Checker;
+use crate::diagnostic::Diagnostic;
+use crate::parser::Parser;
+use crate::tokenizer::Tokenizer;
+use crate::types::{
+    Definition, Expression, ExpressionKind, Span, Spanned, Statement, StatementKind, Struct, Token,
+    TokenType, Type,
+};
+
+/// The functions every program can call without defining them.
+const BUILTINS: [(&str, &str); 2] = [
+    ("print", "fn print(fmt: str, ...)"),
+    ("format", "fn format(fmt: str, ...) -> str"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Struct,
+    Global,
+    /// A local variable or parameter.
+    Local,
+    Field,
+}
+
+/// A name declared in the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The name where it is declared.
+    pub name_span: Span,
+    /// The whole declaration.
+    pub span: Span,
+    /// How the declaration reads, e.g. `fn add(a: int, b: int) -> int` or `let x: int`.
+    pub detail: String,
+    /// The type of a variable, if it is known.
+    pub t: Option<Type>,
+    pub doc: Option<String>,
+    /// Where a local can be used. Definitions can be used everywhere.
+    scope: Option<Span>,
+}
+
+/// A suggestion for the identifier being typed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub label: String,
+    pub kind: SymbolKind,
+    pub detail: String,
+}
+
+/// What an editor wants to know about a single file: every declaration, what each name refers
+/// to and the types of the expressions.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    /// The text that was analysed, which the spans are offsets into.
+    text: String,
+    tokens: Vec<Token>,
+    symbols: Vec<Symbol>,
+    /// The span of each use of a name and the symbol it refers to.
+    references: Vec<(Span, usize)>,
+    /// The field names of field accesses, with the type of the field if it is known.
+    fields: Vec<(Span, String, Option<Type>)>,
+    structs: HashMap<String, Struct>,
+    /// Type errors. A file with syntax errors has no analysis.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Analysis {
+    /// Analyse the file `text`, or return its syntax errors. Type errors do not stop the
+    /// analysis, and are kept in `diagnostics`.
+    pub fn new(text: &str) -> Result<Analysis, Vec<Diagnostic>> {
+        let tokens = Tokenizer::new(0).tokenize(text)?;
+        let ast = Parser::new(tokens.clone()).parse()?;
+        let mut checker = Checker::new();
+        let diagnostics = match checker.check(&ast) {
+            Ok(()) => vec![],
+            Err(errors) => errors.into_iter().map(Diagnostic::from).collect(),
+        };
+
+        let mut analysis = Analysis {
+            text: text.to_string(),
+            tokens,
+            symbols: vec![],
+            references: vec![],
+            fields: vec![],
+            structs: HashMap::new(),
+            diagnostics,
+        };
+        let mut resolver = Resolver {
+            types: checker.expression_types(),
+            values: HashMap::new(),
+            structs: HashMap::new(),
+            scopes: vec![],
+            block_end: 0,
+            analysis: &mut analysis,
+        };
+        resolver.definitions(&ast.definitions);
+
+        Ok(analysis)
+    }
+
+    /// The top level definitions, in the order they are written.
+    pub fn definitions(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter().filter(|symbol| symbol.scope.is_none())
+    }
+
+    /// The declaration of the name at `offset`.
+    pub fn definition_at(&self, offset: usize) -> Option<&Symbol> {
+        let (span, name) = self.identifier_at(offset)?;
+        if let Some((_, symbol)) = self.references.iter().find(|(s, _)| *s == span) {
+            return Some(&self.symbols[*symbol]);
+        }
+        if let Some(symbol) = self.symbols.iter().find(|s| s.name_span == span) {
+            return Some(symbol);
+        }
+        // Names in types, such as the `Pos` of `p: Pos`, can only be structs.
+        self.definitions()
+            .find(|s| s.kind == SymbolKind::Struct && s.name == name)
+    }
+
+    /// Markdown describing the name at `offset`, and the span of the name.
+    pub fn hover_at(&self, offset: usize) -> Option<(String, Span)> {
+        let (span, _) = self.identifier_at(offset)?;
+        let (detail, doc) = match self.definition_at(offset) {
+            Some(symbol) => (symbol.detail.clone(), symbol.doc.as_deref()),
+            None => {
+                let (_, field, t) = self.fields.iter().find(|(s, ..)| *s == span)?;
+                (describe(field, t.as_ref()), None)
+            }
+        };
+        let mut markdown = format!("```samlang\n{}\n```", detail);
+        if let Some(doc) = doc {
+            markdown.push_str("\n\n");
+            markdown.push_str(doc);
+        }
+        Some((markdown, span))
+    }
+
+    /// The names that can be written at `offset` of `text`, which may have changed since it
+    /// was analysed. After a `.` these are the fields of the struct before it, and otherwise
+    /// every variable, function and struct in scope.
+    pub fn completions(&self, text: &str, offset: usize) -> Vec<Completion> {
+        let analysed = self.analysed_offset(text, offset);
+        let tokens = Tokenizer::new(0).tokenize(text).unwrap_or_default();
+        let mut before: Vec<&Token> = tokens
+            .iter()
+            .filter(|token| token.span.end <= offset && !is_trivia(&token.token_type))
+            .collect();
+        // The identifier being typed is replaced by the completion.
+        if let Some(Token {
+            token_type: TokenType::Identifier(_),
+            span,
+            ..
+        }) = before.last()
+        {
+            if span.end == offset {
+                before.pop();
+            }
+        }
+
+        if !matches!(before.last(), Some(token) if token.token_type == TokenType::Dot) {
+            return self.completions_in_scope(analysed);
+        }
+        // Collect the names of `a.b.` backwards.
+        let mut path = vec![];
+        while let [.., name, dot] = before[..] {
+            match (&name.token_type, &dot.token_type) {
+                (TokenType::Identifier(name), TokenType::Dot) => path.push(name.clone()),
+                _ => break,
+            }
+            before.truncate(before.len() - 2);
+        }
+        let Some(first) = path.pop() else {
+            return vec![];
+        };
+        let mut t = self
+            .visible_at(analysed)
+            .remove(first.as_str())
+            .and_then(|s| s.t.clone());
+        while let Some(field) = path.pop() {
+            t = t.and_then(|t| self.field_type(&t, &field));
+        }
+
+        match t.and_then(|t| self.struct_of(&t)) {
+            Some(s) => s
+                .fields
+                .iter()
+                .map(|(field, t)| Completion {
+                    label: field.node.clone(),
+                    kind: SymbolKind::Field,
+                    detail: describe(&field.node, Some(&t.node)),
+                })
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Where `offset` of `text` is in the analysed text, taking `text` to be the analysed text
+    /// with one region edited. Offsets within the edited region map to its start.
+    fn analysed_offset(&self, text: &str, offset: usize) -> usize {
+        let (old, new) = (self.text.as_bytes(), text.as_bytes());
+        let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        if offset <= prefix {
+            offset
+        } else if offset >= new.len() - suffix {
+            offset + old.len() - new.len()
+        } else {
+            prefix
+        }
+    }
+
+    fn completions_in_scope(&self, offset: usize) -> Vec<Completion> {
+        let visible = self.visible_at(offset);
+        let mut completions: Vec<Completion> = visible
+            .values()
+            .map(|symbol| Completion {
+                label: symbol.name.clone(),
+                kind: symbol.kind,
+                detail: symbol.detail.clone(),
+            })
+            .collect();
+        for (name, detail) in BUILTINS {
+            if !visible.contains_key(name) {
+                completions.push(Completion {
+                    label: name.to_string(),
+                    kind: SymbolKind::Function,
+                    detail: detail.to_string(),
+                });
+            }
+        }
+        completions.sort_by(|a, b| a.label.cmp(&b.label));
+        completions
+    }
+
+    /// The symbols that a name at `offset` can refer to, by name. Locals shadow definitions,
+    /// and later locals shadow earlier ones.
+    fn visible_at(&self, offset: usize) -> HashMap<&str, &Symbol> {
+        let mut visible = HashMap::new();
+        for symbol in self.definitions() {
+            // A global shadows a function of the same name.
+            if symbol.kind != SymbolKind::Function || !visible.contains_key(symbol.name.as_str()) {
+                visible.insert(symbol.name.as_str(), symbol);
+            }
+        }
+        for symbol in &self.symbols {
+            if let Some(scope) = symbol.scope {
+                if symbol.name_span.end <= offset && offset <= scope.end {
+                    visible.insert(symbol.name.as_str(), symbol);
+                }
+            }
+        }
+        visible
+    }
+
+    fn struct_of(&self, t: &Type) -> Option<&Struct> {
+        match t {
+            Type::Struct(name) => self.structs.get(name),
+            _ => None,
+        }
+    }
+
+    fn field_type(&self, t: &Type, field: &str) -> Option<Type> {
+        self.struct_of(t)?.field(field).cloned()
+    }
+
+    /// The identifier touching `offset`, so that the cursor may be right after the name.
+    fn identifier_at(&self, offset: usize) -> Option<(Span, &str)> {
+        self.tokens
+            .iter()
+            .find_map(|token| match &token.token_type {
+                TokenType::Identifier(name)
+                    if token.span.start <= offset && offset <= token.span.end =>
+                {
+                    Some((token.span, name.as_str()))
+                }
+                _ => None,
+            })
+    }
+}
+
+fn is_trivia(token_type: &TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::Whitespace | TokenType::Newline | TokenType::Comment | TokenType::DocComment(_)
+    )
+}
+
+/// `name: t`, or just `name` if the type is not known.
+fn describe(name: &str, t: Option<&Type>) -> String {
+    match t {
+        Some(t) => format!("{}: {}", name, t),
+        None => name.to_string(),
+    }
+}
+
+/// Walks the syntax tree, declaring a symbol for every name and resolving every use of one.
+struct Resolver<'a> {
+    types: &'a HashMap<Span, Type>,
+    /// Functions and globals by name.
+    values: HashMap<String, usize>,
+    structs: HashMap<String, usize>,
+    /// The locals of the function being walked, innermost last.
+    scopes: Vec<HashMap<String, usize>>,
+    /// Where the block being walked ends, which is where its locals go out of scope.
+    block_end: usize,
+    analysis: &'a mut Analysis,
+}
+
+impl Resolver<'_> {
+    fn definitions(&mut self, definitions: &[Definition]) {
+        for def in definitions {
+            let symbol = match def {
+                Definition::FnDef(function) => {
+                    let params: Vec<String> = function
+                        .params
+                        .iter()
+                        .map(|(name, t)| describe(&name.node, Some(&t.node)))
+                        .collect();
+                    let mut detail = format!("fn {}({})", function.name, params.join(", "));
+                    if let Some(t) = &function.return_type {
+                        detail.push_str(&format!(" -> {}", t.node));
+                    }
+                    Symbol {
+                        name: function.name.clone(),
+                        kind: SymbolKind::Function,
+                        name_span: function.name_span,
+                        span: function.span,
+                        detail,
+                        t: Some(function.value_type()),
+                        doc: function.doc.clone(),
+                        scope: None,
+                    }
+                }
+                Definition::StructDef(s) => {
+                    self.analysis.structs.insert(s.name.clone(), s.clone());
+                    let fields: Vec<String> = s
+                        .fields
+                        .iter()
+                        .map(|(name, t)| describe(&name.node, Some(&t.node)))
+                        .collect();
+                    Symbol {
+                        name: s.name.clone(),
+                        kind: SymbolKind::Struct,
+                        name_span: s.name_span,
+                        span: s.span,
+                        detail: format!("struct {} {{ {} }}", s.name, fields.join(", ")),
+                        t: None,
+                        doc: s.doc.clone(),
+                        scope: None,
+                    }
+                }
+                Definition::GlobalDef(name, annotation, expr, span, doc) => {
+                    let t = annotation
+                        .as_ref()
+                        .map(|t| t.node.clone())
+                        .or_else(|| self.types.get(&expr.span).cloned());
+                    Symbol {
+                        name: name.node.clone(),
+                        kind: SymbolKind::Global,
+                        name_span: name.span,
+                        span: *span,
+                        detail: format!("let {}", describe(&name.node, t.as_ref())),
+                        t,
+                        doc: doc.clone(),
+                        scope: None,
+                    }
+                }
+            };
+            let index = self.analysis.symbols.len();
+            match symbol.kind {
+                SymbolKind::Struct => {
+                    self.structs.entry(symbol.name.clone()).or_insert(index);
+                }
+                // Globals shadow functions of the same name.
+                SymbolKind::Function => {
+                    self.values.entry(symbol.name.clone()).or_insert(index);
+                }
+                _ => {
+                    self.values.insert(symbol.name.clone(), index);
+                }
+            }
+            self.analysis.symbols.push(symbol);
+        }
+
+        for def in definitions {
+            match def {
+                Definition::FnDef(function) => {
+                    self.scopes.push(HashMap::new());
+                    for (name, t) in &function.params {
+                        self.declare(name, function.span, Some(t.node.clone()));
+                    }
+                    self.statement(&function.body);
+                    self.scopes.pop();
+                }
+                Definition::GlobalDef(_, _, expr, ..) => self.expression(expr),
+                Definition::StructDef(_) => {}
+            }
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match &stmt.kind {
+            StatementKind::Block(stmts) => {
+                let block_end = std::mem::replace(&mut self.block_end, stmt.span.end);
+                self.scopes.push(HashMap::new());
+                for stmt in stmts.iter() {
+                    self.statement(stmt);
+                }
+                self.scopes.pop();
+                self.block_end = block_end;
+            }
+            StatementKind::DeclStmt(name, annotation, expr) => {
+                self.expression(expr);
+                let t = annotation
+                    .as_ref()
+                    .map(|t| t.node.clone())
+                    .or_else(|| self.types.get(&expr.span).cloned());
+                // A local can be used until the end of its block.
+                let scope = Span {
+                    end: self.block_end.max(stmt.span.end),
+                    ..stmt.span
+                };
+                let symbol = self.declare(name, scope, t);
+                self.analysis.symbols[symbol].span = stmt.span;
+                self.analysis.symbols[symbol].detail =
+                    format!("let {}", self.analysis.symbols[symbol].detail);
+            }
+            StatementKind::AssignStmt(target, expr) => {
+                self.expression(target);
+                self.expression(expr);
+            }
+            StatementKind::ExprStmt(expr) | StatementKind::RetStmt(Some(expr)) => {
+                self.expression(expr)
+            }
+            StatementKind::IfStmt(cond, then, otherwise) => {
+                self.expression(cond);
+                self.statement(then);
+                if let Some(otherwise) = otherwise {
+                    self.statement(otherwise);
+                }
+            }
+            StatementKind::IfLetStmt(name, expr, then, otherwise) => {
+                self.expression(expr);
+                let t = match self.types.get(&expr.span) {
+                    Some(Type::Optional(t)) => Some(*t.clone()),
+                    _ => None,
+                };
+                self.scopes.push(HashMap::new());
+                self.declare(name, name.span.to(then.span), t);
+                self.statement(then);
+                self.scopes.pop();
+                if let Some(otherwise) = otherwise {
+                    self.statement(otherwise);
+                }
+            }
+            StatementKind::WhileStmt(cond, body) => {
+                self.expression(cond);
+                self.statement(body);
+            }
+            StatementKind::ForStmt(name, iter, body) => {
+                self.expression(iter);
+                self.scopes.push(HashMap::new());
+                self.declare(name, stmt.span, Some(Type::String));
+                self.statement(body);
+                self.scopes.pop();
+            }
+            StatementKind::RetStmt(None) | StatementKind::EmptyStmt => {}
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        match &expr.kind {
+            ExpressionKind::Literal(_) => {}
+            ExpressionKind::VarAccess(name) => self.reference(name, expr.span),
+            ExpressionKind::FnCall(callee, args) => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+            ExpressionKind::UnaryOp(_, operand) => self.expression(operand),
+            ExpressionKind::BinaryOp(_, lhs, rhs) => {
+                self.expression(lhs);
+                self.expression(rhs);
+            }
+            ExpressionKind::StructLiteral(name, fields) => {
+                if let Some(name) = name {
+                    if let Some(&symbol) = self.structs.get(name) {
+                        let span = Span {
+                            end: expr.span.start + name.len(),
+                            ..expr.span
+                        };
+                        self.analysis.references.push((span, symbol));
+                    }
+                }
+                for (_, value) in fields {
+                    self.expression(value);
+                }
+            }
+            ExpressionKind::FieldAccess(base, field) => {
+                self.expression(base);
+                let span = Span {
+                    start: expr.span.end - field.len(),
+                    ..expr.span
+                };
+                let t = self.types.get(&expr.span).cloned();
+                self.analysis.fields.push((span, field.clone(), t));
+            }
+            ExpressionKind::AnonFnDef(f) => {
+                self.scopes.push(HashMap::new());
+                for (name, t) in &f.params {
+                    self.declare(name, expr.span, Some(t.node.clone()));
+                }
+                self.statement(&f.body);
+                self.scopes.pop();
+            }
+        }
+    }
+
+    /// Declare a local that can be used in `scope` and return its symbol.
+    fn declare(&mut self, name: &Spanned<String>, scope: Span, t: Option<Type>) -> usize {
+        let index = self.analysis.symbols.len();
+        self.analysis.symbols.push(Symbol {
+            name: name.node.clone(),
+            kind: SymbolKind::Local,
+            name_span: name.span,
+            span: name.span,
+            detail: describe(&name.node, t.as_ref()),
+            t,
+            doc: None,
+            scope: Some(scope),
+        });
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.node.clone(), index);
+        }
+        index
+    }
+
+    fn reference(&mut self, name: &str, span: Span) {
+        let symbol = self
+            .lookup_local(name)
+            .or_else(|| self.values.get(name).copied());
+        if let Some(symbol) = symbol {
+            self.analysis.references.push((span, symbol));
+        }
+    }
+
+    fn lookup_local(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn declarations_point_at_their_names() {
+        // The first `b` after `f` is the type of `a`, not the parameter.
+        let source = "struct b {}\nfn f(a: b, b: int) -> int { let c = |b: int|: int { return b; }; return b; }";
+        let analysis = Analysis::new(source).unwrap();
+        let declared_at = |needle: &str| {
+            let offset = source.rfind(needle).unwrap();
+            let span = analysis.definition_at(offset).unwrap().name_span;
+            span.start
+        };
+        assert_eq!(declared_at("b; }"), source.find("b: int").unwrap());
+        assert_eq!(declared_at("b; };"), source.find("b: int|").unwrap());
+        assert_eq!(declared_at("b,"), 7);
+    }
+
+    #[test]
+    fn completions_follow_edits_since_the_analysis() {
+        let source = "fn f() {\n    let a = 1;\n}\nfn g() {\n    let b = 2;\n    \n}\n";
+        let analysis = Analysis::new(source).unwrap();
+        let labels = |text: &str, offset: usize| -> Vec<String> {
+            let completions = analysis.completions(text, offset);
+            completions.into_iter().map(|c| c.label).collect()
+        };
+
+        // Lines added to `f` move `g` further down.
+        let edited = source.replace("1;\n", "1;\n    let broken = (\n    let x = 3;\n");
+        let offset = edited.rfind("\n}").unwrap();
+        let visible = labels(&edited, offset);
+        assert!(visible.contains(&"b".to_string()));
+        assert!(!visible.contains(&"a".to_string()));
+
+        // Typing in `f` only sees what was declared before the edit.
+        let edited = source.replace("1;\n", "1;\n    let c = 2;\n    c");
+        let offset = edited.find("    c").unwrap() + 5;
+        let visible = labels(&edited, offset);
+        assert!(visible.contains(&"a".to_string()));
+        assert!(!visible.contains(&"b".to_string()));
+    }
+}
@@ -0,0 +1,11 @@
+//! The samlang language server. Editors start it and speak the Language Server Protocol over
+//! its standard input and output.
+use std::io;
+use std::process;
+
+fn main() {
+    if let Err(err) = slang::lsp::serve(io::stdin().lock(), io::stdout().lock()) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
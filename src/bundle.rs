@@ -0,0 +1,134 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::source::SourceMap;
+
+/// Marks an executable with a program appended to it.
+const MAGIC: &[u8; 8] = b"SLANGEXE";
+
+/// The length of the length and `MAGIC` that end an executable with a program.
+const TRAILER_LEN: u64 = 16;
+
+/// Write a standalone executable for the program in `sources` to `out`.
+///
+/// The executable is a copy of the running `slang` binary with the sources appended, followed
+/// by their length and `MAGIC`. On startup `embedded_sources` finds them and runs the program
+/// instead of reading the command line. Nothing is compiled ahead of time: the sources are
+/// parsed and checked again every time the executable starts.
+pub fn write_executable(sources: &SourceMap, out: &Path) -> io::Result<()> {
+    let mut payload = encode(sources);
+    payload.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    payload.extend_from_slice(MAGIC);
+
+    fs::copy(std::env::current_exe()?, out)?;
+    OpenOptions::new()
+        .append(true)
+        .open(out)?
+        .write_all(&payload)
+}
+
+/// The sources appended to the running executable by `write_executable`, if any.
+pub fn embedded_sources() -> Option<SourceMap> {
+    let exe = File::open(std::env::current_exe().ok()?).ok()?;
+    decode(&read_payload(exe)?)
+}
+
+/// The payload before the trailer at the end of `file`, if it has one.
+fn read_payload(mut file: impl Read + Seek) -> Option<Vec<u8>> {
+    let file_len = file.seek(SeekFrom::End(0)).ok()?;
+    let mut trailer = [0; TRAILER_LEN as usize];
+    file.seek(SeekFrom::Start(file_len.checked_sub(TRAILER_LEN)?))
+        .ok()?;
+    file.read_exact(&mut trailer).ok()?;
+    if &trailer[8..] != MAGIC {
+        return None;
+    }
+
+    // A corrupt length must not make us allocate more than the file could hold.
+    let len = u64::from_le_bytes(trailer[..8].try_into().ok()?);
+    if len > file_len - TRAILER_LEN {
+        return None;
+    }
+    let mut payload = vec![0; usize::try_from(len).ok()?];
+    file.seek(SeekFrom::Start(file_len - TRAILER_LEN - len))
+        .ok()?;
+    file.read_exact(&mut payload).ok()?;
+    Some(payload)
+}
+
+/// The name and text of every file, each prefixed with its length.
+fn encode(sources: &SourceMap) -> Vec<u8> {
+    let mut payload = vec![];
+    for file in sources.files() {
+        for part in [&file.name, &file.text] {
+            payload.extend_from_slice(&(part.len() as u64).to_le_bytes());
+            payload.extend_from_slice(part.as_bytes());
+        }
+    }
+    payload
+}
+
+fn decode(mut payload: &[u8]) -> Option<SourceMap> {
+    let mut sources = SourceMap::new();
+    while !payload.is_empty() {
+        let name = take(&mut payload)?;
+        let text = take(&mut payload)?;
+        sources.add(name, text);
+    }
+
+    Some(sources)
+}
+
+/// Split a string prefixed with its length off the front of `bytes`.
+fn take(bytes: &mut &[u8]) -> Option<String> {
+    let (len, rest) = bytes.split_at_checked(8)?;
+    let len = usize::try_from(u64::from_le_bytes(len.try_into().ok()?)).ok()?;
+    let (part, rest) = rest.split_at_checked(len)?;
+    *bytes = rest;
+    String::from_utf8(part.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sources_survive_a_round_trip() {
+        let mut sources = SourceMap::new();
+        sources.add(
+            "main.sk".to_string(),
+            "fn main() -> int { return 0; }".to_string(),
+        );
+        sources.add("ö.sk".to_string(), String::new());
+
+        let decoded = decode(&encode(&sources)).unwrap();
+        let files: Vec<(&str, &str)> = decoded
+            .files()
+            .iter()
+            .map(|f| (f.name.as_str(), f.text.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![("main.sk", "fn main() -> int { return 0; }"), ("ö.sk", "")]
+        );
+        assert!(decode(&encode(&sources)[..12]).is_none());
+    }
+
+    #[test]
+    fn trailers_are_validated() {
+        let file = |payload: &[u8], len: u64| {
+            let mut bytes = b"binary".to_vec();
+            bytes.extend_from_slice(payload);
+            bytes.extend_from_slice(&len.to_le_bytes());
+            bytes.extend_from_slice(MAGIC);
+            io::Cursor::new(bytes)
+        };
+        assert_eq!(read_payload(file(b"abc", 3)), Some(b"abc".to_vec()));
+        assert_eq!(read_payload(file(b"abc", 9)), Some(b"binaryabc".to_vec()));
+        assert_eq!(read_payload(file(b"abc", 10)), None);
+        assert_eq!(read_payload(file(b"abc", u64::MAX)), None);
+        assert_eq!(read_payload(io::Cursor::new(b"short".to_vec())), None);
+        assert_eq!(read_payload(io::Cursor::new(vec![0; 64])), None);
+    }
+}
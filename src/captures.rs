@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+
+use crate::types::{Definition, Expression, ExpressionKind, Statement, StatementKind, AST};
+
+/// Fill in `AnonFn::captures` for every anonymous function in the tree.
+///
+/// A capture is a local of an enclosing function (or anonymous function) that the body uses.
+/// Names that do not resolve to a local refer to globals or functions and are not captured.
+pub fn analyze(ast: &mut AST) {
+    for def in &mut ast.definitions {
+        match def {
+            Definition::FnDef(function) => {
+                let params = function.params.iter().map(|(name, _)| name.node.clone());
+                let mut analyzer = Analyzer {
+                    scopes: vec![params.collect()],
+                    closures: vec![],
+                };
+                analyzer.statement(&mut function.body);
+            }
+            Definition::GlobalDef(_, _, expr, ..) => {
+                let mut analyzer = Analyzer {
+                    scopes: vec![],
+                    closures: vec![],
+                };
+                analyzer.expression(expr);
+            }
+            Definition::StructDef(_) => {}
+        }
+    }
+}
+
+struct Analyzer {
+    scopes: Vec<HashSet<String>>,
+    /// The anonymous functions being analysed, innermost last: the index of the scope holding
+    /// their parameters and the captures found so far.
+    closures: Vec<(usize, Vec<String>)>,
+}
+
+impl Analyzer {
+    fn resolve(&mut self, name: &str) {
+        let Some(depth) = self.scopes.iter().rposition(|s| s.contains(name)) else {
+            return;
+        };
+        // Every anonymous function between the use and the definition captures the name, so
+        // that nested functions can pass it on.
+        for (start, captures) in &mut self.closures {
+            if depth < *start && !captures.iter().any(|c| c == name) {
+                captures.push(name.to_string());
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string());
+        }
+    }
+
+    fn statement(&mut self, stmt: &mut Statement) {
+        match &mut stmt.kind {
+            StatementKind::Block(stmts) => {
+                self.scopes.push(HashSet::new());
+                for stmt in stmts.iter_mut() {
+                    self.statement(stmt);
+                }
+                self.scopes.pop();
+            }
+            StatementKind::DeclStmt(name, _, expr) => {
+                self.expression(expr);
+                self.declare(&name.node);
+            }
+            StatementKind::ForStmt(name, iter, body) => {
+                self.expression(iter);
+                self.scopes.push(HashSet::from([name.node.clone()]));
+                self.statement(body);
+                self.scopes.pop();
+            }
+            StatementKind::WhileStmt(cond, body) => {
+                self.expression(cond);
+                self.statement(body);
+            }
+            StatementKind::IfStmt(cond, then, otherwise) => {
+                self.expression(cond);
+                self.statement(then);
+                if let Some(otherwise) = otherwise {
+                    self.statement(otherwise);
+                }
+            }
+            StatementKind::IfLetStmt(name, expr, then, otherwise) => {
+                self.expression(expr);
+                self.scopes.push(HashSet::from([name.node.clone()]));
+                self.statement(then);
+                self.scopes.pop();
+                if let Some(otherwise) = otherwise {
+                    self.statement(otherwise);
+                }
+            }
+            StatementKind::AssignStmt(target, expr) => {
+                self.expression(target);
+                self.expression(expr);
+            }
+            StatementKind::ExprStmt(expr) | StatementKind::RetStmt(Some(expr)) => {
+                self.expression(expr)
+            }
+            StatementKind::RetStmt(None) | StatementKind::EmptyStmt => {}
+        }
+    }
+
+    fn expression(&mut self, expr: &mut Expression) {
+        match &mut expr.kind {
+            ExpressionKind::VarAccess(name) => self.resolve(name),
+            ExpressionKind::FnCall(callee, args) => {
+                self.expression(callee);
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+            ExpressionKind::BinaryOp(_, lhs, rhs) => {
+                self.expression(lhs);
+                self.expression(rhs);
+            }
+            ExpressionKind::UnaryOp(_, operand) => self.expression(operand),
+            ExpressionKind::StructLiteral(_, fields) => {
+                for (_, value) in fields {
+                    self.expression(value);
+                }
+            }
+            ExpressionKind::FieldAccess(base, _) => self.expression(base),
+            ExpressionKind::Literal(_) => {}
+            ExpressionKind::AnonFnDef(f) => {
+                self.closures.push((self.scopes.len(), vec![]));
+                self.scopes
+                    .push(f.params.iter().map(|(name, _)| name.node.clone()).collect());
+                self.statement(&mut f.body);
+                self.scopes.pop();
+                if let Some((_, captures)) = self.closures.pop() {
+                    f.captures = captures;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1436 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::diagnostic::Diagnostic;
+use crate::types::{
+    Definition, Expression, ExpressionKind, Function, Literal, Op, Span, Spanned, Statement,
+    StatementKind, Struct, Type, AST,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeErrorKind {
+    Mismatch {
+        expected: Type,
+        found: Type,
+    },
+    UnknownVariable(String),
+    UnknownFunction(String),
+    UnknownType(String),
+    UnknownField(Type, String),
+    MissingField(String, String),
+    DuplicateDefinition(String),
+    /// `name` is `None` when the callee is not a name, as in `make()(1)`.
+    ArgumentCount {
+        name: Option<String>,
+        expected: usize,
+        found: usize,
+    },
+    UnaryOperand(Op, Type),
+    BinaryOperands(Op, Type, Type),
+    UninferableStruct,
+    UninferableNone,
+    NotOptional(Type),
+    NotCallable(Type),
+    NotIterable(Type),
+    VoidValue,
+    FormatString,
+    /// A function with a return type that can reach its end. `None` for anonymous functions.
+    MissingReturn(Option<String>),
+    AssignToFunction(String),
+    AssignToCapture(String),
+    FormatArguments {
+        placeholders: usize,
+        found: usize,
+    },
+    MissingMain,
+    /// `main` with a type other than `||: int` or `||: void`.
+    InvalidMain(Type),
+    /// A global initializer uses `function`, which uses `global` before it is initialised,
+    /// possibly through other functions.
+    UninitializedGlobal {
+        function: String,
+        global: String,
+    },
+}
+
+impl fmt::Display for TypeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeErrorKind::Mismatch { expected, found } => {
+                write!(f, "expected `{}`, found `{}`", expected, found)
+            }
+            TypeErrorKind::UnknownVariable(name) => write!(f, "unknown variable `{}`", name),
+            TypeErrorKind::UnknownFunction(name) => write!(f, "unknown function `{}`", name),
+            TypeErrorKind::UnknownType(name) => write!(f, "unknown type `{}`", name),
+            TypeErrorKind::UnknownField(t, field) => {
+                write!(f, "`{}` has no field `{}`", t, field)
+            }
+            TypeErrorKind::MissingField(name, field) => {
+                write!(f, "missing field `{}` in `{}`", field, name)
+            }
+            TypeErrorKind::DuplicateDefinition(name) => {
+                write!(f, "`{}` is defined more than once", name)
+            }
+            TypeErrorKind::ArgumentCount {
+                name: Some(name),
+                expected,
+                found,
+            } => write!(
+                f,
+                "`{}` takes {} argument(s) but {} were given",
+                name, expected, found
+            ),
+            TypeErrorKind::ArgumentCount {
+                name: None,
+                expected,
+                found,
+            } => write!(
+                f,
+                "function takes {} argument(s) but {} were given",
+                expected, found
+            ),
+            TypeErrorKind::UnaryOperand(op, t) => {
+                write!(f, "cannot apply {:?} to `{}`", op, t)
+            }
+            TypeErrorKind::BinaryOperands(op, lhs, rhs) => {
+                write!(f, "cannot apply {:?} to `{}` and `{}`", op, lhs, rhs)
+            }
+            TypeErrorKind::UninferableStruct => {
+                write!(f, "cannot infer the struct type of this literal")
+            }
+            TypeErrorKind::UninferableNone => write!(f, "cannot infer the type of `none`"),
+            TypeErrorKind::NotOptional(t) => write!(f, "`{}` is not an optional type", t),
+            TypeErrorKind::NotCallable(t) => write!(f, "`{}` is not a function", t),
+            TypeErrorKind::NotIterable(t) => write!(f, "cannot iterate over `{}`", t),
+            TypeErrorKind::VoidValue => write!(f, "expression has type `void` and no value"),
+            TypeErrorKind::FormatString => write!(f, "expected a format string"),
+            TypeErrorKind::MissingReturn(Some(name)) => {
+                write!(f, "`{}` does not return a value on every path", name)
+            }
+            TypeErrorKind::AssignToFunction(name) => {
+                write!(f, "cannot assign to function `{}`", name)
+            }
+            TypeErrorKind::AssignToCapture(name) => {
+                write!(f, "cannot assign to captured variable `{}`", name)
+            }
+            TypeErrorKind::FormatArguments {
+                placeholders,
+                found,
+            } => write!(
+                f,
+                "format string has {} placeholder(s) but {} argument(s) were given",
+                placeholders, found
+            ),
+            TypeErrorKind::MissingMain => write!(f, "no `main` function defined"),
+            TypeErrorKind::InvalidMain(t) => write!(f, "`main` cannot have type `{}`", t),
+            TypeErrorKind::UninitializedGlobal { function, global } => write!(
+                f,
+                "`{}` uses the global `{}` before it is initialised",
+                function, global
+            ),
+            TypeErrorKind::MissingReturn(None) => {
+                write!(
+                    f,
+                    "anonymous function does not return a value on every path"
+                )
+            }
+        }
+    }
+}
+
+/// A semantic error found by the `Checker`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeError {
+    pub span: Span,
+    pub kind: TypeErrorKind,
+}
+
+impl From<TypeError> for Diagnostic {
+    fn from(err: TypeError) -> Diagnostic {
+        let diagnostic = Diagnostic::error(err.span, err.kind.to_string());
+        match err.kind {
+            TypeErrorKind::Mismatch { expected, .. } => {
+                diagnostic.with_label(format!("expected `{}`", expected))
+            }
+            TypeErrorKind::ArgumentCount { expected, .. } => {
+                diagnostic.with_label(format!("expected {} argument(s)", expected))
+            }
+            TypeErrorKind::UninferableStruct => diagnostic.with_note(
+                "name the struct, e.g. `Point { x = 1 }`, or annotate the binding".to_string(),
+            ),
+            TypeErrorKind::UninferableNone => {
+                diagnostic.with_note("annotate the binding, e.g. `let x: int? = none;`".to_string())
+            }
+            TypeErrorKind::VoidValue => {
+                diagnostic.with_note("functions without a return type return `void`".to_string())
+            }
+            TypeErrorKind::AssignToCapture(_) => diagnostic.with_note(
+                "anonymous functions get a copy of the variables they capture".to_string(),
+            ),
+            TypeErrorKind::FormatArguments { placeholders, .. } => {
+                diagnostic.with_label(format!("expected {} after the format string", placeholders))
+            }
+            TypeErrorKind::MissingMain => diagnostic
+                .with_label("expected `fn main` in the program".to_string())
+                .with_note("programs start at `fn main()` or `fn main() -> int`".to_string()),
+            TypeErrorKind::InvalidMain(_) => diagnostic.with_note(
+                "programs start at `fn main()` or `fn main() -> int`, which take no arguments"
+                    .to_string(),
+            ),
+            TypeErrorKind::UninitializedGlobal { global, .. } => diagnostic
+                .with_label("used while initialising globals".to_string())
+                .with_note(format!(
+                    "globals are initialised in declaration order; declare `{}` first",
+                    global
+                )),
+            _ => diagnostic,
+        }
+    }
+}
+
+/// Signature of a callable function.
+#[derive(Debug, Clone)]
+struct Signature {
+    params: Vec<Type>,
+    return_type: Type,
+}
+
+impl Signature {
+    fn of(function: &Function) -> Signature {
+        Signature {
+            params: function
+                .params
+                .iter()
+                .map(|(_, t)| t.node.clone())
+                .collect(),
+            return_type: function
+                .return_type
+                .as_ref()
+                .map_or(Type::Void, |t| t.node.clone()),
+        }
+    }
+}
+
+/// The globals and functions that a piece of code names, and where.
+#[derive(Debug, Default)]
+struct References {
+    globals: Vec<(String, Span)>,
+    functions: Vec<(String, Span)>,
+}
+
+/// Static type checker. It walks every definition and collects all errors instead of stopping
+/// at the first one.
+///
+/// A type of `None` means the expression already produced an error, which keeps one mistake
+/// from being reported again by every expression that uses it.
+pub struct Checker {
+    structs: HashMap<String, Struct>,
+    functions: HashMap<String, Signature>,
+    /// Functions declared from outside, with the span of their signature. Their types are
+    /// checked once the program's structs are known.
+    declared: Vec<(Signature, Span)>,
+    globals: HashMap<String, Option<Type>>,
+    scopes: Vec<HashMap<String, Option<Type>>>,
+    /// Whether the program must define `fn main`, because it is going to be run.
+    require_main: bool,
+    /// The first of `scopes` that belongs to the anonymous function being checked. Variables
+    /// in the scopes before it are captured by value.
+    closure_scope: usize,
+    return_type: Type,
+    errors: Vec<TypeError>,
+    /// The type of every expression checked without errors, by its span.
+    types: HashMap<Span, Type>,
+    /// What the definition being checked names so far.
+    references: References,
+    /// What each function names, anonymous functions in it included.
+    function_references: HashMap<String, References>,
+    /// What each global initializer names, in declaration order.
+    global_references: Vec<(String, References)>,
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Checker {
+    pub fn new() -> Checker {
+        Checker {
+            structs: HashMap::new(),
+            functions: HashMap::new(),
+            declared: vec![],
+            require_main: false,
+            globals: HashMap::new(),
+            scopes: vec![],
+            closure_scope: 0,
+            return_type: Type::Void,
+            errors: vec![],
+            types: HashMap::new(),
+            references: References::default(),
+            function_references: HashMap::new(),
+            global_references: vec![],
+        }
+    }
+
+    /// Make a function defined outside the program, such as a host function, callable.
+    /// Unknown types in it are reported at `span`.
+    pub fn declare_function(
+        &mut self,
+        name: &str,
+        params: Vec<Type>,
+        return_type: Type,
+        span: Span,
+    ) {
+        let signature = Signature {
+            params,
+            return_type,
+        };
+        self.declared.push((signature.clone(), span));
+        self.functions.insert(name.to_string(), signature);
+    }
+
+    /// Report a program without `fn main`. Programs that are only called into from Rust do not
+    /// need one.
+    pub fn require_main(&mut self) {
+        self.require_main = true;
+    }
+
+    /// Check the whole program, returning every error found.
+    pub fn check(&mut self, ast: &AST) -> Result<(), Vec<TypeError>> {
+        for def in &ast.definitions {
+            if let Definition::StructDef(s) = def {
+                if self.structs.insert(s.name.clone(), s.clone()).is_some() {
+                    self.error(
+                        s.name_span,
+                        TypeErrorKind::DuplicateDefinition(s.name.clone()),
+                    );
+                }
+            }
+        }
+
+        for (signature, span) in std::mem::take(&mut self.declared) {
+            for t in signature.params.iter().chain([&signature.return_type]) {
+                self.check_type(t, span);
+            }
+        }
+
+        for def in &ast.definitions {
+            match def {
+                Definition::StructDef(s) => {
+                    for (_, t) in &s.fields {
+                        self.check_type(&t.node, t.span);
+                    }
+                }
+                Definition::FnDef(function) => {
+                    for t in function.params.iter().map(|(_, t)| t) {
+                        self.check_type(&t.node, t.span);
+                    }
+                    if let Some(t) = &function.return_type {
+                        self.check_type(&t.node, t.span);
+                    }
+                    let signature = Signature::of(function);
+                    if self
+                        .functions
+                        .insert(function.name.clone(), signature)
+                        .is_some()
+                    {
+                        self.error(
+                            function.name_span,
+                            TypeErrorKind::DuplicateDefinition(function.name.clone()),
+                        );
+                    }
+                }
+                Definition::GlobalDef(..) => {}
+            }
+        }
+
+        let main = ast.definitions.iter().find_map(|def| match def {
+            Definition::FnDef(function) if function.name == "main" => Some(function),
+            _ => None,
+        });
+        match main {
+            Some(main) => {
+                let signature = Signature::of(main);
+                let valid = matches!(signature.return_type, Type::Void | Type::Int);
+                if !signature.params.is_empty() || !valid {
+                    let t = Type::Function(signature.params, Box::new(signature.return_type));
+                    self.error(main.name_span, TypeErrorKind::InvalidMain(t));
+                }
+            }
+            None if self.require_main => {
+                // There is nothing to point at, so point where `main` could be added: after the
+                // last definition, which is in the last file.
+                let end = ast.definitions.last().map_or(Span::default(), |def| {
+                    let span = def.span();
+                    Span {
+                        start: span.end,
+                        ..span
+                    }
+                });
+                self.error(end, TypeErrorKind::MissingMain);
+            }
+            None => {}
+        }
+
+        // Globals are initialised in declaration order, so each initializer only sees the
+        // globals declared above it.
+        for def in &ast.definitions {
+            if let Definition::GlobalDef(name, annotation, expr, ..) = def {
+                let t = self.check_binding(annotation, expr);
+                if self.globals.insert(name.node.clone(), t).is_some() {
+                    self.error(
+                        name.span,
+                        TypeErrorKind::DuplicateDefinition(name.node.clone()),
+                    );
+                }
+                let references = std::mem::take(&mut self.references);
+                self.global_references.push((name.node.clone(), references));
+            }
+        }
+
+        for def in &ast.definitions {
+            if let Definition::FnDef(function) = def {
+                self.return_type = Signature::of(function).return_type;
+                self.scopes = vec![function
+                    .params
+                    .iter()
+                    .map(|(name, t)| (name.node.clone(), Some(t.node.clone())))
+                    .collect()];
+                self.check_statement(&function.body);
+                if self.return_type != Type::Void && !Self::returns(&function.body) {
+                    self.error(
+                        function.name_span,
+                        TypeErrorKind::MissingReturn(Some(function.name.clone())),
+                    );
+                }
+                let references = std::mem::take(&mut self.references);
+                self.function_references
+                    .insert(function.name.clone(), references);
+            }
+        }
+        self.check_initialization_order();
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    /// Report global initializers that use functions which, directly or through other
+    /// functions, use a global that is not initialised yet: the one being initialised or one
+    /// declared after it. The initializers themselves can only name globals declared above.
+    fn check_initialization_order(&mut self) {
+        let mut order = HashMap::new();
+        for (i, (global, _)) in self.global_references.iter().enumerate() {
+            order.entry(global.as_str()).or_insert(i);
+        }
+
+        let mut errors = vec![];
+        for (i, (_, references)) in self.global_references.iter().enumerate() {
+            for (function, span) in &references.functions {
+                let mut seen = vec![function];
+                let mut pending = vec![function];
+                while let Some(name) = pending.pop() {
+                    // Host functions do not use globals.
+                    let Some(uses) = self.function_references.get(name) else {
+                        continue;
+                    };
+                    let late = uses.globals.iter().find(|(g, _)| order[g.as_str()] >= i);
+                    if let Some((global, _)) = late {
+                        let kind = TypeErrorKind::UninitializedGlobal {
+                            function: function.clone(),
+                            global: global.clone(),
+                        };
+                        errors.push(TypeError { span: *span, kind });
+                        break;
+                    }
+                    for (callee, _) in &uses.functions {
+                        if !seen.contains(&callee) {
+                            seen.push(callee);
+                            pending.push(callee);
+                        }
+                    }
+                }
+            }
+        }
+        self.errors.extend(errors);
+    }
+
+    /// Check `expr` as if it initialised a global declared after every definition of the
+    /// program checked last, and return its type.
+    pub fn check_global_expression(&mut self, expr: &Expression) -> Result<Type, Vec<TypeError>> {
+        self.scopes = vec![];
+        let t = self.check_expression(expr, None);
+        match t {
+            Some(t) if self.errors.is_empty() => Ok(t),
+            _ => Err(std::mem::take(&mut self.errors)),
+        }
+    }
+
+    /// The type of every expression checked so far without errors, by its span. Tools use this
+    /// to show the type of the code under the cursor.
+    pub fn expression_types(&self) -> &HashMap<Span, Type> {
+        &self.types
+    }
+
+    /// Report struct types that were never defined.
+    fn check_type(&mut self, t: &Type, span: Span) -> bool {
+        match t {
+            Type::Struct(name) if !self.structs.contains_key(name) => {
+                self.error(span, TypeErrorKind::UnknownType(name.clone()));
+                false
+            }
+            Type::Function(params, return_type) => {
+                // Check every part so that all unknown types are reported.
+                let mut ok = true;
+                for t in params.iter().chain([return_type.as_ref()]) {
+                    ok &= self.check_type(t, span);
+                }
+                ok
+            }
+            Type::Optional(t) => self.check_type(t, span),
+            _ => true,
+        }
+    }
+
+    /// Check `let [: annotation] = expr` and return the type of the new binding.
+    fn check_binding(
+        &mut self,
+        annotation: &Option<Spanned<Type>>,
+        expr: &Expression,
+    ) -> Option<Type> {
+        match annotation {
+            Some(t) => {
+                if self.check_type(&t.node, t.span) {
+                    self.check_expression_as(expr, &t.node);
+                }
+                Some(t.node.clone())
+            }
+            None => {
+                let t = self.check_expression(expr, None)?;
+                if t == Type::Void {
+                    self.error(expr.span, TypeErrorKind::VoidValue);
+                    return None;
+                }
+                Some(t)
+            }
+        }
+    }
+
+    fn check_statement(&mut self, stmt: &Statement) {
+        match &stmt.kind {
+            StatementKind::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                for stmt in stmts.iter() {
+                    self.check_statement(stmt);
+                }
+                self.scopes.pop();
+            }
+            StatementKind::DeclStmt(name, annotation, expr) => {
+                let t = self.check_binding(annotation, expr);
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert(name.node.clone(), t);
+                }
+            }
+            StatementKind::AssignStmt(target, expr) => match self.check_place(target) {
+                Some(t) => self.check_expression_as(expr, &t),
+                None => {
+                    self.check_expression(expr, None);
+                }
+            },
+            StatementKind::ExprStmt(expr) => {
+                self.check_expression(expr, None);
+            }
+            StatementKind::IfStmt(cond, then, otherwise) => {
+                self.check_expression_as(cond, &Type::Bool);
+                self.check_statement(then);
+                if let Some(otherwise) = otherwise {
+                    self.check_statement(otherwise);
+                }
+            }
+            StatementKind::IfLetStmt(name, expr, then, otherwise) => {
+                let t = match self.check_expression(expr, None) {
+                    Some(Type::Optional(t)) => Some(*t),
+                    Some(t) => {
+                        self.error(expr.span, TypeErrorKind::NotOptional(t));
+                        None
+                    }
+                    None => None,
+                };
+                self.scopes.push(HashMap::from([(name.node.clone(), t)]));
+                self.check_statement(then);
+                self.scopes.pop();
+                if let Some(otherwise) = otherwise {
+                    self.check_statement(otherwise);
+                }
+            }
+            StatementKind::WhileStmt(cond, body) => {
+                self.check_expression_as(cond, &Type::Bool);
+                self.check_statement(body);
+            }
+            StatementKind::ForStmt(name, iter, body) => {
+                let item = match self.check_expression(iter, None) {
+                    Some(Type::String) => Some(Type::String),
+                    Some(t) => {
+                        self.error(iter.span, TypeErrorKind::NotIterable(t));
+                        None
+                    }
+                    None => None,
+                };
+                self.scopes.push(HashMap::from([(name.node.clone(), item)]));
+                self.check_statement(body);
+                self.scopes.pop();
+            }
+            StatementKind::RetStmt(Some(expr)) => {
+                let expected = self.return_type.clone();
+                self.check_expression_as(expr, &expected);
+            }
+            StatementKind::RetStmt(None) => {
+                if self.return_type != Type::Void {
+                    self.error(
+                        stmt.span,
+                        TypeErrorKind::Mismatch {
+                            expected: self.return_type.clone(),
+                            found: Type::Void,
+                        },
+                    );
+                }
+            }
+            StatementKind::EmptyStmt => {}
+        }
+    }
+
+    /// Check the target of an assignment, which the parser only allows to be a name or a field
+    /// of one. The name must be a variable, not a function, and not one captured by the
+    /// anonymous function being checked.
+    fn check_place(&mut self, target: &Expression) -> Option<Type> {
+        let mut root = target;
+        while let ExpressionKind::FieldAccess(base, _) = &root.kind {
+            root = base;
+        }
+        if let ExpressionKind::VarAccess(name) = &root.kind {
+            let scope = self.scopes.iter().rposition(|s| s.contains_key(name));
+            let kind = match scope {
+                Some(i) if i < self.closure_scope => TypeErrorKind::AssignToCapture(name.clone()),
+                None if self.lookup(name).is_none() && self.functions.contains_key(name) => {
+                    TypeErrorKind::AssignToFunction(name.clone())
+                }
+                _ => return self.check_expression(target, None),
+            };
+            self.error(root.span, kind);
+            return None;
+        }
+        self.check_expression(target, None)
+    }
+
+    /// Whether every path through `stmt` ends in a `return`. Both branches of an `if` must
+    /// return, and loops never count because their body may not run.
+    fn returns(stmt: &Statement) -> bool {
+        match &stmt.kind {
+            StatementKind::RetStmt(_) => true,
+            StatementKind::Block(stmts) => stmts.iter().any(Self::returns),
+            StatementKind::IfStmt(_, then, Some(otherwise))
+            | StatementKind::IfLetStmt(_, _, then, Some(otherwise)) => {
+                Self::returns(then) && Self::returns(otherwise)
+            }
+            _ => false,
+        }
+    }
+
+    /// Check that `expr` has type `expected`. A `T` is also accepted where a `T?` is expected.
+    fn check_expression_as(&mut self, expr: &Expression, expected: &Type) {
+        if let Some(found) = self.check_expression(expr, Some(expected)) {
+            let promoted = matches!(expected, Type::Optional(t) if **t == found);
+            if found != *expected && !promoted {
+                self.error(
+                    expr.span,
+                    TypeErrorKind::Mismatch {
+                        expected: expected.clone(),
+                        found,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Compute the type of `expr`. `expected` is the type the context wants, which is used to
+    /// name `{ field = expr }` struct literals.
+    fn check_expression(&mut self, expr: &Expression, expected: Option<&Type>) -> Option<Type> {
+        let t = self.check_expression_kind(expr, expected);
+        if let Some(t) = &t {
+            self.types.insert(expr.span, t.clone());
+        }
+        t
+    }
+
+    fn check_expression_kind(
+        &mut self,
+        expr: &Expression,
+        expected: Option<&Type>,
+    ) -> Option<Type> {
+        match &expr.kind {
+            ExpressionKind::Literal(literal) => Some(match literal {
+                Literal::Int(_) => Type::Int,
+                Literal::Double(_) => Type::Double,
+                Literal::String(_) => Type::String,
+                Literal::Bool(_) => Type::Bool,
+                Literal::None => match expected {
+                    Some(t @ Type::Optional(_)) => t.clone(),
+                    _ => {
+                        self.error(expr.span, TypeErrorKind::UninferableNone);
+                        return None;
+                    }
+                },
+            }),
+            ExpressionKind::VarAccess(name) => {
+                if let Some(t) = self.lookup(name) {
+                    if !self.scopes.iter().any(|s| s.contains_key(name)) {
+                        let global = (name.clone(), expr.span);
+                        self.references.globals.push(global);
+                    }
+                    return t;
+                }
+                let t = self.functions.get(name).map(|signature| {
+                    Type::Function(
+                        signature.params.clone(),
+                        Box::new(signature.return_type.clone()),
+                    )
+                });
+                match t {
+                    Some(t) => {
+                        let function = (name.clone(), expr.span);
+                        self.references.functions.push(function);
+                        Some(t)
+                    }
+                    None => {
+                        self.error(expr.span, TypeErrorKind::UnknownVariable(name.clone()));
+                        None
+                    }
+                }
+            }
+            ExpressionKind::FnCall(callee, args) => self.check_call(expr, callee, args),
+            ExpressionKind::UnaryOp(op, operand) => {
+                let t = self.check_expression(operand, None)?;
+                match (op, &t) {
+                    (Op::Not, Type::Bool) => Some(Type::Bool),
+                    (Op::Sub, Type::Int | Type::Double) => Some(t),
+                    _ => {
+                        self.error(expr.span, TypeErrorKind::UnaryOperand(op.clone(), t));
+                        None
+                    }
+                }
+            }
+            ExpressionKind::BinaryOp(op, lhs, rhs) => {
+                let lhs = self.check_expression(lhs, None);
+                // The left operand gives context to the right one, as in `x == none`.
+                let context = match (op, &lhs) {
+                    (Op::Coalesce, Some(Type::Optional(t))) => Some(t.as_ref()),
+                    (Op::Eq | Op::Neq, Some(t)) => Some(t),
+                    _ => None,
+                };
+                let rhs = self.check_expression(rhs, context);
+                let (lhs, rhs) = (lhs?, rhs?);
+                match Self::binary_op_type(op, &lhs, &rhs) {
+                    Some(t) => Some(t),
+                    None => {
+                        self.error(
+                            expr.span,
+                            TypeErrorKind::BinaryOperands(op.clone(), lhs, rhs),
+                        );
+                        None
+                    }
+                }
+            }
+            ExpressionKind::StructLiteral(name, fields) => {
+                let expected = match expected {
+                    Some(Type::Optional(t)) => Some(t.as_ref()),
+                    expected => expected,
+                };
+                let name = match (name, expected) {
+                    (Some(name), _) => name.clone(),
+                    (None, Some(Type::Struct(name))) => name.clone(),
+                    (None, _) => {
+                        // Without context, infer the only struct with exactly these fields.
+                        let names: Vec<&str> = fields.iter().map(|(f, _)| f.as_str()).collect();
+                        let mut candidates = self.structs.values().filter(|s| s.has_fields(&names));
+                        match (candidates.next(), candidates.next()) {
+                            (Some(s), None) => s.name.clone(),
+                            _ => {
+                                self.error(expr.span, TypeErrorKind::UninferableStruct);
+                                return None;
+                            }
+                        }
+                    }
+                };
+                let def = match self.structs.get(&name) {
+                    Some(def) => def.clone(),
+                    None => {
+                        self.error(expr.span, TypeErrorKind::UnknownType(name));
+                        return None;
+                    }
+                };
+
+                for (field, value) in fields {
+                    match def.field(field) {
+                        Some(t) => self.check_expression_as(value, t),
+                        None => {
+                            self.error(
+                                value.span,
+                                TypeErrorKind::UnknownField(
+                                    Type::Struct(name.clone()),
+                                    field.clone(),
+                                ),
+                            );
+                        }
+                    }
+                }
+                for (field, _) in &def.fields {
+                    if !fields.iter().any(|(f, _)| *f == field.node) {
+                        self.error(
+                            expr.span,
+                            TypeErrorKind::MissingField(name.clone(), field.node.clone()),
+                        );
+                    }
+                }
+
+                Some(Type::Struct(name))
+            }
+            ExpressionKind::FieldAccess(base, field) => {
+                let t = self.check_expression(base, None)?;
+                let found = match &t {
+                    Type::Struct(name) => self
+                        .structs
+                        .get(name)
+                        .and_then(|def| def.field(field).cloned()),
+                    _ => None,
+                };
+                if found.is_none() {
+                    self.error(expr.span, TypeErrorKind::UnknownField(t, field.clone()));
+                }
+                found
+            }
+            ExpressionKind::AnonFnDef(f) => {
+                for t in f.params.iter().map(|(_, t)| t).chain(&f.return_type) {
+                    self.check_type(&t.node, t.span);
+                }
+                let f_return_type = f
+                    .return_type
+                    .as_ref()
+                    .map_or(Type::Void, |t| t.node.clone());
+
+                // The body sees the enclosing scopes, which is where its captures come from.
+                let return_type = std::mem::replace(&mut self.return_type, f_return_type);
+                let closure_scope = std::mem::replace(&mut self.closure_scope, self.scopes.len());
+                self.scopes.push(
+                    f.params
+                        .iter()
+                        .map(|(name, t)| (name.node.clone(), Some(t.node.clone())))
+                        .collect(),
+                );
+                self.check_statement(&f.body);
+                self.scopes.pop();
+                self.closure_scope = closure_scope;
+                let f_return_type = std::mem::replace(&mut self.return_type, return_type);
+                if f_return_type != Type::Void && !Self::returns(&f.body) {
+                    self.error(expr.span, TypeErrorKind::MissingReturn(None));
+                }
+
+                Some(Type::Function(
+                    f.params.iter().map(|(_, t)| t.node.clone()).collect(),
+                    Box::new(f_return_type),
+                ))
+            }
+        }
+    }
+
+    /// The type of a local or global variable. `Some(None)` if it exists but has no valid type.
+    fn lookup(&self, name: &str) -> Option<Option<Type>> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|s| s.get(name))
+            .or_else(|| self.globals.get(name))
+            .cloned()
+    }
+
+    fn check_call(
+        &mut self,
+        expr: &Expression,
+        callee: &Expression,
+        args: &[Expression],
+    ) -> Option<Type> {
+        // Variables holding functions shadow functions of the same name, and the builtins.
+        let name = match &callee.kind {
+            ExpressionKind::VarAccess(name) => Some(name),
+            _ => None,
+        };
+        if let Some(name) = name {
+            if self.lookup(name).is_none() && !self.functions.contains_key(name) {
+                if name == "print" || name == "format" {
+                    return self.check_format(expr, name, args);
+                }
+                self.error(expr.span, TypeErrorKind::UnknownFunction(name.clone()));
+                for arg in args {
+                    self.check_expression(arg, None);
+                }
+                return None;
+            }
+        }
+        let signature = match self.check_expression(callee, None) {
+            Some(Type::Function(params, return_type)) => Signature {
+                params,
+                return_type: *return_type,
+            },
+            t => {
+                if let Some(t) = t {
+                    self.error(expr.span, TypeErrorKind::NotCallable(t));
+                }
+                for arg in args {
+                    self.check_expression(arg, None);
+                }
+                return None;
+            }
+        };
+
+        if signature.params.len() != args.len() {
+            self.error(
+                expr.span,
+                TypeErrorKind::ArgumentCount {
+                    name: name.cloned(),
+                    expected: signature.params.len(),
+                    found: args.len(),
+                },
+            );
+        }
+        for (arg, param) in args.iter().zip(&signature.params) {
+            self.check_expression_as(arg, param);
+        }
+        for arg in args.iter().skip(signature.params.len()) {
+            self.check_expression(arg, None);
+        }
+
+        Some(signature.return_type)
+    }
+
+    /// `print` and `format` take a format string followed by any number of values.
+    fn check_format(&mut self, expr: &Expression, name: &str, args: &[Expression]) -> Option<Type> {
+        match args.first() {
+            Some(fmt) => self.check_expression_as(fmt, &Type::String),
+            None => self.error(expr.span, TypeErrorKind::FormatString),
+        }
+        // Every `{}` takes the next argument, which a literal format string lets us count.
+        if let Some(ExpressionKind::Literal(Literal::String(fmt))) = args.first().map(|a| &a.kind) {
+            let placeholders = fmt.matches("{}").count();
+            if placeholders != args.len() - 1 {
+                self.error(
+                    expr.span,
+                    TypeErrorKind::FormatArguments {
+                        placeholders,
+                        found: args.len() - 1,
+                    },
+                );
+            }
+        }
+        for arg in args.iter().skip(1) {
+            if let Some(Type::Void) = self.check_expression(arg, None) {
+                self.error(arg.span, TypeErrorKind::VoidValue);
+            }
+        }
+
+        Some(if name == "print" {
+            Type::Void
+        } else {
+            Type::String
+        })
+    }
+
+    /// The result type of a binary operator, or `None` if the operands are not supported.
+    fn binary_op_type(op: &Op, lhs: &Type, rhs: &Type) -> Option<Type> {
+        match (op, lhs, rhs) {
+            (Op::Add, Type::String, Type::String) => Some(Type::String),
+            (Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod, Type::Int, Type::Int) => {
+                Some(Type::Int)
+            }
+            (Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod, Type::Double, Type::Double) => {
+                Some(Type::Double)
+            }
+            (Op::BitAnd | Op::BitOr | Op::BitXor | Op::Shl | Op::Shr, Type::Int, Type::Int) => {
+                Some(Type::Int)
+            }
+            (Op::And | Op::Or | Op::BitAnd | Op::BitOr | Op::BitXor, Type::Bool, Type::Bool) => {
+                Some(Type::Bool)
+            }
+            // `a ?? b` is a `T` if `b` is, or a `T?` if `b` may be `none` as well.
+            (Op::Coalesce, Type::Optional(t), rhs) if **t == *rhs => Some(rhs.clone()),
+            (Op::Coalesce, Type::Optional(_), rhs) if lhs == rhs => Some(rhs.clone()),
+            (Op::Eq | Op::Neq, lhs, rhs) if lhs == rhs && *lhs != Type::Void => Some(Type::Bool),
+            (Op::Lt | Op::Gt | Op::Lte | Op::Gte, Type::Int | Type::Double | Type::String, rhs)
+                if lhs == rhs =>
+            {
+                Some(Type::Bool)
+            }
+            _ => None,
+        }
+    }
+
+    fn error(&mut self, span: Span, kind: TypeErrorKind) {
+        self.errors.push(TypeError { span, kind });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+
+    fn check(source: &str) -> Result<(), Vec<TypeErrorKind>> {
+        let tokens = Tokenizer::new(0).tokenize(source).unwrap();
+        let ast = Parser::new(tokens).parse().unwrap();
+        Checker::new()
+            .check(&ast)
+            .map_err(|errors| errors.into_iter().map(|e| e.kind).collect())
+    }
+
+    #[test]
+    fn well_typed_program() {
+        let source = "
+            struct Pos { x: int, y: int }
+            let origin: Pos = { x = 0, y = 0 };
+            fn fib(n: int) -> int {
+                if n <= 1 { return n; }
+                return fib(n-1) + fib(n-2);
+            }
+            fn main() -> int {
+                let p = Pos { x: fib(3), y: origin.y };
+                print(\"{} {}\", p, \"!\");
+                return p.x;
+            }
+        ";
+        assert_eq!(check(source), Ok(()));
+    }
+
+    #[test]
+    fn annotation_mismatch() {
+        assert_eq!(
+            check("fn main() { let x: int = \"a\"; }"),
+            Err(vec![TypeErrorKind::Mismatch {
+                expected: Type::Int,
+                found: Type::String
+            }])
+        );
+    }
+
+    #[test]
+    fn calls_check_arity_and_arguments() {
+        let source = "
+            fn f(a: int, b: str) -> int { return a; }
+            fn main() {
+                f(1);
+                f(\"1\", \"2\");
+                g();
+            }
+        ";
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::ArgumentCount {
+                    name: Some("f".to_string()),
+                    expected: 2,
+                    found: 1
+                },
+                TypeErrorKind::Mismatch {
+                    expected: Type::Int,
+                    found: Type::String
+                },
+                TypeErrorKind::UnknownFunction("g".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn anything_that_is_a_function_can_be_called() {
+        let source = "
+            struct Scaler { by: |int|: int }
+            fn yeet(n: int) -> |int|: int { return |x: int|: int { return x * n; }; }
+            fn main() {
+                let a: int = yeet(3)(7);
+                let s = Scaler { by: yeet(2) };
+                let b: int = s.by(a);
+                yeet(1)(1, 2);
+                s.by(\"x\");
+                (a)(1);
+            }
+        ";
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::ArgumentCount {
+                    name: None,
+                    expected: 1,
+                    found: 2
+                },
+                TypeErrorKind::Mismatch {
+                    expected: Type::Int,
+                    found: Type::String
+                },
+                TypeErrorKind::NotCallable(Type::Int),
+            ])
+        );
+    }
+
+    #[test]
+    fn operator_operands() {
+        let source = "
+            fn main() {
+                let a = 1 + \"a\";
+                let b = !1;
+                let c = 1 < 2 && 3;
+                let d = a + 1;
+            }
+        ";
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::BinaryOperands(Op::Add, Type::Int, Type::String),
+                TypeErrorKind::UnaryOperand(Op::Not, Type::Int),
+                TypeErrorKind::BinaryOperands(Op::And, Type::Bool, Type::Int),
+            ])
+        );
+    }
+
+    #[test]
+    fn return_types() {
+        let source = "
+            fn f() -> int { return \"a\"; }
+            fn g() -> int { return; }
+            fn h() { return 1; }
+        ";
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::Mismatch {
+                    expected: Type::Int,
+                    found: Type::String
+                },
+                TypeErrorKind::Mismatch {
+                    expected: Type::Int,
+                    found: Type::Void
+                },
+                TypeErrorKind::Mismatch {
+                    expected: Type::Void,
+                    found: Type::Int
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn every_path_must_return() {
+        let source = "
+            fn a(x: int) -> int { if x > 0 { return 1; } }
+            fn b(x: int) -> int { if x > 0 { return 1; } else if x < 0 { return -1; } else { return 0; } }
+            fn c(x: int?) -> int { if let y = x { return y; } else { print(\"none\"); } }
+            fn d() -> int { while true { return 1; } }
+            fn e() -> int {}
+            fn f() { }
+            fn g() -> |int|: int { return |x: int|: int { print(\"{}\", x); }; }
+        ";
+        let missing = |name: &str| TypeErrorKind::MissingReturn(Some(name.to_string()));
+        assert_eq!(
+            check(source),
+            Err(vec![
+                missing("a"),
+                missing("c"),
+                missing("d"),
+                missing("e"),
+                TypeErrorKind::MissingReturn(None),
+            ])
+        );
+    }
+
+    #[test]
+    fn only_variables_can_be_assigned() {
+        let source = "
+            fn a() {}
+            fn b() {}
+            let g = 1;
+            fn main() {
+                a = b;
+                a.x = 1;
+                g = 2;
+                let b = a;
+                b = main;
+                c = 3;
+            }
+        ";
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::AssignToFunction("a".to_string()),
+                TypeErrorKind::AssignToFunction("a".to_string()),
+                TypeErrorKind::UnknownVariable("c".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn captured_variables_cannot_be_assigned() {
+        let source = "
+            struct Pos { x: int }
+            let total = 0;
+            fn main() {
+                let c = 0;
+                let p: Pos = { x = 0 };
+                let f = |n: int|: void {
+                    let d = n;
+                    d += 1;
+                    total += n;
+                    c += 1;
+                    p.x = n;
+                    let g = |m: int|: void { d = m; };
+                };
+                c += 1;
+            }
+        ";
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::AssignToCapture("c".to_string()),
+                TypeErrorKind::AssignToCapture("p".to_string()),
+                TypeErrorKind::AssignToCapture("d".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn format_strings_match_their_arguments() {
+        let source = "
+            fn main() {
+                print(\"{} and {}\", 1, 2);
+                print(\"{}\");
+                let s = format(\"none\", 1);
+                let fmt = \"{}\";
+                print(fmt, 1, 2);
+            }
+        ";
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::FormatArguments {
+                    placeholders: 1,
+                    found: 0
+                },
+                TypeErrorKind::FormatArguments {
+                    placeholders: 0,
+                    found: 1
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn main_takes_nothing_and_returns_an_int_or_nothing() {
+        assert_eq!(check("fn main() -> int { return 0; }"), Ok(()));
+        assert_eq!(
+            check("fn main(n: int) -> str { return \"\"; }"),
+            Err(vec![TypeErrorKind::InvalidMain(Type::Function(
+                vec![Type::Int],
+                Box::new(Type::String)
+            ))])
+        );
+
+        let ast = Parser::new(Tokenizer::new(0).tokenize("fn f() {}").unwrap())
+            .parse()
+            .unwrap();
+        assert_eq!(Checker::new().check(&ast), Ok(()));
+        let mut checker = Checker::new();
+        checker.require_main();
+        let errors = checker.check(&ast).unwrap_err();
+        assert_eq!(errors[0].kind, TypeErrorKind::MissingMain);
+        assert_eq!((errors[0].span.start, errors[0].span.end), (9, 9));
+    }
+
+    #[test]
+    fn struct_errors() {
+        let source = "
+            struct Pos { x: int, y: Nope }
+            fn main() {
+                let p: Pos = { x = 1, z = 2 };
+                let q = { x = 1 };
+                let r = p.w;
+            }
+        ";
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::UnknownType("Nope".to_string()),
+                TypeErrorKind::UnknownField(Type::Struct("Pos".to_string()), "z".to_string()),
+                TypeErrorKind::MissingField("Pos".to_string(), "y".to_string()),
+                TypeErrorKind::UninferableStruct,
+                TypeErrorKind::UnknownField(Type::Struct("Pos".to_string()), "w".to_string()),
+            ])
+        );
+
+        // Unknown types point at the annotation they are written in.
+        let source = "fn f(a: int, b: |int|: Nope?) {}";
+        let tokens = Tokenizer::new(0).tokenize(source).unwrap();
+        let ast = Parser::new(tokens).parse().unwrap();
+        let errors = Checker::new().check(&ast).unwrap_err();
+        let span = errors[0].span;
+        assert_eq!(&source[span.start..span.end], "|int|: Nope?");
+    }
+
+    #[test]
+    fn infers_function_values() {
+        let source = "
+            fn greet(name: str, day: str) {}
+            fn main() {
+                let x = greet;
+                x(\"legolas\", \"friday\");
+                x(1, \"friday\");
+                let y: int = x;
+                let n = 1;
+                n();
+            }
+        ";
+        let greet = Type::Function(vec![Type::String, Type::String], Box::new(Type::Void));
+        assert_eq!(greet.to_string(), "|str, str|: void");
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::Mismatch {
+                    expected: Type::String,
+                    found: Type::Int
+                },
+                TypeErrorKind::Mismatch {
+                    expected: Type::Int,
+                    found: greet
+                },
+                TypeErrorKind::NotCallable(Type::Int),
+            ])
+        );
+    }
+
+    #[test]
+    fn infers_struct_literals_from_their_fields() {
+        let source = "
+            struct Pos { x: int, y: int }
+            struct Size { w: int, h: int }
+            struct Other { w: int, h: int }
+            fn main() -> int {
+                let p = { y = 1, x = 2 };
+                let s = { w = 1, h = 2 };
+                return p.x + p.y;
+            }
+        ";
+        assert_eq!(check(source), Err(vec![TypeErrorKind::UninferableStruct]));
+    }
+
+    #[test]
+    fn anonymous_functions() {
+        let source = "
+            fn yeet(factor: int) -> |int|: int {
+                return |x: int|: int {
+                    return x * factor;
+                };
+            }
+            fn main() -> int {
+                let f = yeet(2);
+                let g: |int|: str = |x: int|: int { return \"a\"; };
+                return f(21);
+            }
+        ";
+        let int_to_int = Type::Function(vec![Type::Int], Box::new(Type::Int));
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::Mismatch {
+                    expected: Type::Int,
+                    found: Type::String
+                },
+                TypeErrorKind::Mismatch {
+                    expected: Type::Function(vec![Type::Int], Box::new(Type::String)),
+                    found: int_to_int
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn optional_types() {
+        let source = "
+            struct Pos { x: int }
+            fn find(n: int) -> Pos? {
+                if n < 0 { return none; }
+                return { x = n };
+            }
+            fn main() -> int {
+                let p = find(1);
+                if let q = p { return q.x; }
+                let n: int? = 1;
+                let m = none;
+                let k: int = n ?? 2 == 3;
+                if p == none || n == 1 { return p.x; }
+                return (n ?? 0) + (n ?? none);
+            }
+        ";
+        let optional_int = Type::Optional(Box::new(Type::Int));
+        assert_eq!(
+            check(source),
+            Err(vec![
+                TypeErrorKind::UninferableNone,
+                TypeErrorKind::Mismatch {
+                    expected: Type::Int,
+                    found: Type::Bool
+                },
+                TypeErrorKind::BinaryOperands(Op::Eq, optional_int.clone(), Type::Int),
+                TypeErrorKind::UnknownField(
+                    Type::Optional(Box::new(Type::Struct("Pos".to_string()))),
+                    "x".to_string()
+                ),
+                TypeErrorKind::UninferableNone,
+            ])
+        );
+        assert_eq!(
+            check("fn main() { if let x = 1 { } }"),
+            Err(vec![TypeErrorKind::NotOptional(Type::Int)])
+        );
+    }
+
+    #[test]
+    fn globals_only_see_earlier_globals() {
+        assert_eq!(
+            check("let a = b; let b = 1;"),
+            Err(vec![TypeErrorKind::UnknownVariable("b".to_string())])
+        );
+        let uninitialized = |function: &str, global: &str| TypeErrorKind::UninitializedGlobal {
+            function: function.to_string(),
+            global: global.to_string(),
+        };
+        assert_eq!(
+            check("let a: int = f(); let b: int = 2; fn f() -> int { return b; }"),
+            Err(vec![uninitialized("f", "b")])
+        );
+        assert_eq!(
+            check(
+                "let a = g(); let b = 2; fn f() -> int { return b; } fn g() -> int { return f(); }"
+            ),
+            Err(vec![uninitialized("g", "b")])
+        );
+        assert_eq!(
+            check("let a = f(); fn f() -> int { return a + 1; }"),
+            Err(vec![uninitialized("f", "a")])
+        );
+        assert_eq!(
+            check("let b = 2; let a = f(); fn f() -> int { let a = 1; return a + b; }"),
+            Ok(())
+        );
+        assert_eq!(
+            check("let a = f(); fn f() -> int { return f(); } fn main() -> int { return a; }"),
+            Ok(())
+        );
+    }
+}
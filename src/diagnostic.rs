@@ -0,0 +1,165 @@
+use std::fmt;
+
+use crate::source::SourceMap;
+use crate::types::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A message attached to a region of the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A problem found in a source file, shared by every compiler stage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Where the problem is.
+    pub primary: Label,
+    /// Related positions, such as a previous definition.
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(span: Span, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message,
+            primary: Label {
+                span,
+                message: String::new(),
+            },
+            secondary: vec![],
+            notes: vec![],
+        }
+    }
+
+    pub fn with_label(mut self, message: String) -> Diagnostic {
+        self.primary.message = message;
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: String) -> Diagnostic {
+        self.secondary.push(Label { span, message });
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+
+    /// Render the diagnostic the way rustc does, quoting the source lines it refers to.
+    /// # Example:
+    /// ```text
+    /// error: expected `int`, found `str`
+    ///  --> main.sk:2:18
+    ///   |
+    /// 2 |     let x: int = "a";
+    ///   |                  ^^^ expected `int`
+    /// ```
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let mut labels = vec![&self.primary];
+        labels.extend(self.secondary.iter());
+        let width = labels
+            .iter()
+            .map(|label| sources.range(label.span).0.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = " ".repeat(width);
+
+        let file = sources.get(self.primary.span.file_id);
+        let (start, _) = sources.range(self.primary.span);
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+        out.push_str(&format!(
+            "{}--> {}:{}:{}\n",
+            gutter, file.name, start.line, start.col
+        ));
+        out.push_str(&format!("{} |\n", gutter));
+        for (i, label) in labels.iter().enumerate() {
+            let (start, end) = sources.range(label.span);
+            let Some(line) = sources.get(label.span.file_id).line(start.line) else {
+                continue;
+            };
+            // Spans covering several lines are underlined to the end of the first one.
+            let end_col = if end.line == start.line {
+                end.col
+            } else {
+                line.chars().count() as i64 + 1
+            };
+            let indent: String = line
+                .chars()
+                .take(start.col as usize - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let underline =
+                if i == 0 { "^" } else { "-" }.repeat((end_col - start.col).max(1) as usize);
+            out.push_str(&format!("{:>width$} | {}\n", start.line, line));
+            out.push_str(
+                format!("{} | {}{} {}", gutter, indent, underline, label.message).trim_end(),
+            );
+            out.push('\n');
+        }
+        for note in &self.notes {
+            out.push_str(&format!("{} = note: {}\n", gutter, note));
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn span(start: usize, end: usize) -> Span {
+        Span {
+            file_id: 0,
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn renders_source_line_with_carets() {
+        let mut sources = SourceMap::new();
+        sources.add(
+            "main.sk".to_string(),
+            "fn main() {\n    let x: int = \"a\";\n}\n".to_string(),
+        );
+        let diagnostic = Diagnostic::error(span(29, 32), "expected `int`, found `str`".to_string())
+            .with_label("expected `int`".to_string())
+            .with_secondary(span(3, 7), "in this function".to_string())
+            .with_note("strings are not numbers".to_string());
+
+        assert_eq!(
+            diagnostic.render(&sources),
+            "error: expected `int`, found `str`
+ --> main.sk:2:18
+  |
+2 |     let x: int = \"a\";
+  |                  ^^^ expected `int`
+1 | fn main() {
+  |    ---- in this function
+  = note: strings are not numbers
+"
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use crate::diagnostic::Diagnostic;
+use crate::parser::Parser;
+use crate::tokenizer::Tokenizer;
+use crate::types::{Keyword, Op, Token, TokenType};
+
+/// How a token is spaced from its neighbours.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    /// `(` or `[`
+    Open,
+    /// `)` or `]`
+    Close,
+    LeftCurly,
+    RightCurly,
+    /// The `|` starting a parameter list.
+    PipeOpen,
+    /// The `|` ending a parameter list.
+    PipeClose,
+    /// Prefix `-` and `!`.
+    Unary,
+    /// Binary operators, `=`, compound assignments and `->`.
+    Binary,
+    Comma,
+    Semicolon,
+    Colon,
+    Dot,
+    Question,
+    /// An identifier, which the `(` of a call follows without a space.
+    Name,
+    /// Literals, keywords, comments and `||`.
+    Word,
+}
+
+/// Format the file `text`, or return its syntax errors. Only files that parse are formatted, so
+/// that mistakes are never rearranged. That includes constructs the language does not have
+/// yet, such as `interface` and `impl`.
+pub fn format_file(file_id: usize, text: &str) -> Result<String, Vec<Diagnostic>> {
+    let tokens = Tokenizer::new(file_id).tokenize(text)?;
+    Parser::new(tokens.clone()).parse()?;
+    Ok(format(text, &tokens))
+}
+
+/// Print the source back in the canonical style: four spaces of indentation per line with
+/// open brackets, single spaces between tokens where they belong, and at most one blank line
+/// in a row. Comments and blank lines are kept, and the text of every token is copied as
+/// written, so formatting never changes what a program means.
+///
+/// `tokens` must come from `source` and parse without errors. Formatting is idempotent.
+pub fn format(source: &str, tokens: &[Token]) -> String {
+    let mut out = String::new();
+    // The line each unclosed bracket was opened on. Several brackets opened on the same line
+    // only indent the lines below by one level.
+    let mut open: Vec<usize> = vec![];
+    let mut blank = false;
+    let mut opens_block = false;
+    for (i, line) in lines(tokens).iter().enumerate() {
+        if line.is_empty() {
+            blank = true;
+            continue;
+        }
+
+        let closers = line
+            .iter()
+            .take_while(|(_, role)| matches!(role, Role::Close | Role::RightCurly))
+            .count();
+        open.truncate(open.len().saturating_sub(closers));
+        // Blank lines are kept between lines, but not at the start or end of a block.
+        if blank && !out.is_empty() && !opens_block && closers == 0 {
+            out.push('\n');
+        }
+        blank = false;
+
+        let mut levels = open.clone();
+        levels.dedup();
+        out.push_str(&"    ".repeat(levels.len()));
+        let mut prev = None;
+        for (j, (token, role)) in line.iter().enumerate() {
+            if prev.is_some_and(|prev| space_between(prev, *role)) {
+                out.push(' ');
+            }
+            out.push_str(source[token.span.start..token.span.end].trim_end());
+            match role {
+                Role::Open | Role::LeftCurly => open.push(i),
+                Role::Close | Role::RightCurly if j >= closers => {
+                    open.pop();
+                }
+                _ => {}
+            }
+            prev = Some(*role);
+        }
+        out.push('\n');
+        opens_block = matches!(prev, Some(Role::Open | Role::LeftCurly));
+    }
+
+    out
+}
+
+/// Split the tokens into source lines without whitespace, giving each token its role.
+fn lines(tokens: &[Token]) -> Vec<Vec<(&Token, Role)>> {
+    let mut lines = vec![vec![]];
+    let mut depth = 0usize;
+    // The bracket depths of the parameter lists being read.
+    let mut pipes: Vec<usize> = vec![];
+    // Whether the previous token ends an operand, which makes `-` and `|` binary operators.
+    let mut after_operand = false;
+    for token in tokens {
+        let role = match &token.token_type {
+            TokenType::Newline => {
+                lines.push(vec![]);
+                continue;
+            }
+            TokenType::Whitespace | TokenType::EOF => continue,
+            TokenType::LeftParen | TokenType::LeftBracket => {
+                depth += 1;
+                Role::Open
+            }
+            TokenType::RightParen | TokenType::RightBracket => {
+                depth = depth.saturating_sub(1);
+                Role::Close
+            }
+            TokenType::LeftCurly => {
+                depth += 1;
+                Role::LeftCurly
+            }
+            TokenType::RightCurly => {
+                depth = depth.saturating_sub(1);
+                Role::RightCurly
+            }
+            TokenType::Pipe if pipes.last() == Some(&depth) => {
+                pipes.pop();
+                Role::PipeClose
+            }
+            TokenType::Pipe if after_operand => Role::Binary,
+            TokenType::Pipe => {
+                pipes.push(depth);
+                Role::PipeOpen
+            }
+            // `||` is an empty parameter list where an operand is expected.
+            TokenType::Operator(Op::Or) if !after_operand => Role::Word,
+            TokenType::Operator(Op::Not) => Role::Unary,
+            TokenType::Operator(Op::Sub) if !after_operand => Role::Unary,
+            TokenType::Operator(_)
+            | TokenType::Assign
+            | TokenType::AssignOp(_)
+            | TokenType::RetArrow => Role::Binary,
+            TokenType::Comma => Role::Comma,
+            TokenType::Semicolon => Role::Semicolon,
+            TokenType::Colon => Role::Colon,
+            TokenType::Dot => Role::Dot,
+            TokenType::Question => Role::Question,
+            TokenType::Identifier(_) => Role::Name,
+            _ => Role::Word,
+        };
+        if !matches!(
+            token.token_type,
+            TokenType::Comment | TokenType::DocComment(_)
+        ) {
+            after_operand = matches!(
+                token.token_type,
+                TokenType::Identifier(_)
+                    | TokenType::Number(_)
+                    | TokenType::Float(_)
+                    | TokenType::StrLiteral(_)
+                    | TokenType::Keyword(Keyword::True | Keyword::False | Keyword::None)
+                    | TokenType::RightParen
+                    | TokenType::RightBracket
+            );
+        }
+        if let Some(line) = lines.last_mut() {
+            line.push((token, role));
+        }
+    }
+
+    lines
+}
+
+/// Whether a space separates two tokens on the same line.
+fn space_between(prev: Role, next: Role) -> bool {
+    let tight_before = matches!(
+        next,
+        Role::Close
+            | Role::Comma
+            | Role::Semicolon
+            | Role::Colon
+            | Role::Dot
+            | Role::Question
+            | Role::PipeClose
+    );
+    let tight_after = matches!(prev, Role::Open | Role::Dot | Role::Unary | Role::PipeOpen);
+    let call = prev == Role::Name && next == Role::Open;
+    let empty_block = prev == Role::LeftCurly && next == Role::RightCurly;
+    !(tight_before || tight_after || call || empty_block)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tokenizer::Tokenizer;
+
+    fn fmt(source: &str) -> String {
+        let tokens = Tokenizer::new(0).tokenize(source).unwrap();
+        format(source, &tokens)
+    }
+
+    #[test]
+    fn formats_in_canonical_style() {
+        let source = "
+
+
+// fib
+fn fib(n:int)->int{
+  if n<=1 {   return n; }   // base case
+   return fib(n-1)+fib( n-2 );
+
+
+}
+struct P{x:int?,y:int}
+fn main( ) -> int {
+
+    let f = |x :int| :int { return -x*2; };
+    let g: |int|: int = f;
+    let h = || : int { return 0x1F | 1; };
+    let p: P = {x=none,y=!true&&1<2};
+    print(\"{}\",   p.x ?? 0);
+    return g(
+      fib(3),
+    );
+}
+";
+        let expected = "// fib
+fn fib(n: int) -> int {
+    if n <= 1 { return n; } // base case
+    return fib(n - 1) + fib(n - 2);
+}
+struct P { x: int?, y: int }
+fn main() -> int {
+    let f = |x: int|: int { return -x * 2; };
+    let g: |int|: int = f;
+    let h = ||: int { return 0x1F | 1; };
+    let p: P = { x = none, y = !true && 1 < 2 };
+    print(\"{}\", p.x ?? 0);
+    return g(
+        fib(3),
+    );
+}
+";
+        assert_eq!(fmt(source), expected);
+        assert_eq!(fmt(expected), expected);
+    }
+
+    #[test]
+    fn reindents_blocks_and_drops_trailing_whitespace() {
+        let source = "struct Pos {
+  x: int,
+  y: int,
+}
+
+let x: Pos = { x = 1, y = 2};
+
+fn fib(n: int) -> int {
+   if n <= 1 {
+        return n;
+   } \t
+   let get = || : int { return n; };
+   return fib(n-1) + fib(n-2);
+}
+";
+        let expected = "struct Pos {
+    x: int,
+    y: int,
+}
+
+let x: Pos = { x = 1, y = 2 };
+
+fn fib(n: int) -> int {
+    if n <= 1 {
+        return n;
+    }
+    let get = ||: int { return n; };
+    return fib(n - 1) + fib(n - 2);
+}
+";
+        assert_eq!(fmt(source), expected);
+        assert_eq!(fmt(expected), expected);
+    }
+
+    #[test]
+    fn files_that_do_not_parse_are_refused() {
+        let errors = format_file(0, include_str!("../tests/basic_interface.sk")).unwrap_err();
+        assert_eq!(errors[0].message, "expected a top level definition");
+        assert!(format_file(0, "fn main() { \"unterminated }").is_err());
+        assert_eq!(
+            format_file(0, "fn main(){}"),
+            Ok("fn main() {}\n".to_string())
+        );
+    }
+
+    #[test]
+    fn nested_brackets_on_one_line_indent_once() {
+        let source =
+            "fn main() {\nlet x = apply(|n: int|: int {\nreturn n;\n});\n/* a\n   b */\n}\n";
+        let expected = "fn main() {
+    let x = apply(|n: int|: int {
+        return n;
+    });
+    /* a
+   b */
+}
+";
+        assert_eq!(fmt(source), expected);
+        assert_eq!(fmt(expected), expected);
+    }
+}
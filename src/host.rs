@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::interpreter::Value;
+use crate::parser::Parser;
+use crate::source::SourceMap;
+use crate::tokenizer::Tokenizer;
+use crate::types::{Span, Type};
+use crate::{Diagnostic, Diagnostics, Program};
+
+/// The Rust side of a host function. It gets the arguments, and an `Err` becomes a runtime
+/// error in the program.
+pub type NativeFn = Arc<dyn Fn(Vec<Value>) -> Result<Value, String> + Send + Sync>;
+
+/// A function the embedding program provides to scripts.
+#[derive(Clone)]
+pub struct HostFunction {
+    pub params: Vec<Type>,
+    pub return_type: Type,
+    pub function: NativeFn,
+}
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let signature = Type::Function(self.params.clone(), Box::new(self.return_type.clone()));
+        write!(f, "HostFunction({})", signature)
+    }
+}
+
+/// The host functions available to programs, which are checked and called like functions
+/// defined in the program.
+/// # Example:
+/// ```
+/// let mut host = slang::Host::new();
+/// host.register("shout", "|str|: str", |args| match &args[0] {
+///     slang::Value::Str(s) => Ok(slang::Value::Str(s.to_uppercase())),
+///     _ => Err("expected a string".to_string()),
+/// })
+/// .unwrap();
+///
+/// let program = host.compile("fn main() { print(shout(\"hi\")); }").unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Host {
+    functions: HashMap<String, HostFunction>,
+}
+
+impl Host {
+    pub fn new() -> Host {
+        Host::default()
+    }
+
+    /// Register `function` as `name`. `signature` is the samlang function type it is checked
+    /// against, e.g. `|str, int|: bool`. Registering a name again replaces the function.
+    pub fn register(
+        &mut self,
+        name: &str,
+        signature: &str,
+        function: impl Fn(Vec<Value>) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> Result<(), Diagnostics> {
+        let mut sources = SourceMap::new();
+        let file_id = sources.add(format!("<signature of `{}`>", name), signature.to_string());
+        let parsed = Tokenizer::new(file_id)
+            .tokenize(signature)
+            .and_then(|tokens| Parser::new(tokens).parse_type_only());
+        let (params, return_type) = match parsed {
+            Ok(Type::Function(params, return_type)) => (params, *return_type),
+            Ok(t) => {
+                let span = Span {
+                    file_id,
+                    start: 0,
+                    end: signature.len(),
+                };
+                let diagnostic =
+                    Diagnostic::error(span, format!("expected a function type, found `{}`", t))
+                        .with_note("signatures are written like `|str, int|: bool`".to_string());
+                return Err(Diagnostics::new(sources, vec![diagnostic]));
+            }
+            Err(diagnostics) => return Err(Diagnostics::new(sources, diagnostics)),
+        };
+
+        self.functions.insert(
+            name.to_string(),
+            HostFunction {
+                params,
+                return_type,
+                function: Arc::new(function),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&HostFunction> {
+        self.functions.get(name)
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = (&String, &HostFunction)> {
+        self.functions.iter()
+    }
+
+    /// Compile a single source file that may call the host functions.
+    pub fn compile(&self, source: &str) -> Result<Program, Diagnostics> {
+        let mut sources = SourceMap::new();
+        sources.add("main.sk".to_string(), source.to_string());
+        Program::compile_with_host(sources, self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::RuntimeError;
+
+    fn host() -> Host {
+        let mut host = Host::new();
+        host.register("lookup", "|int|: str?", |args| match args[0] {
+            Value::Int(1) => Ok(Value::Str("sam".to_string())),
+            Value::Int(n) if n < 0 => Err(format!("bad id {}", n)),
+            _ => Ok(Value::None),
+        })
+        .unwrap();
+        host.register("broken", "||: int", |_| Ok(Value::Str("oops".to_string())))
+            .unwrap();
+        host
+    }
+
+    #[test]
+    fn programs_call_host_functions() {
+        let program = host()
+            .compile(
+                "
+                fn apply(f: |int|: str?, n: int) -> str? { return f(n); }
+                fn name(id: int) -> str { return lookup(id) ?? \"nobody\"; }
+                fn main() -> int {
+                    if let s = apply(lookup, 1) { return 1; }
+                    return 0;
+                }
+                ",
+            )
+            .unwrap();
+        assert_eq!(program.run(), Ok(Value::Int(1)));
+        assert_eq!(
+            program.call("name", vec![Value::Int(2)]),
+            Ok(Value::Str("nobody".to_string()))
+        );
+        assert!(program.call("name", vec![Value::Int(-1)]).is_err());
+
+        let program = host()
+            .compile("fn main() -> int { return broken(); }")
+            .unwrap();
+        assert!(matches!(program.run(), Err(RuntimeError { .. })));
+    }
+
+    #[test]
+    fn host_signatures_are_checked() {
+        let errors = host()
+            .compile("fn main() { let s: str = lookup(\"1\"); }")
+            .unwrap_err();
+        let messages: Vec<&str> = errors.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "expected `int`, found `str`",
+                "expected `str`, found `str?`"
+            ]
+        );
+        assert!(host().compile("fn lookup() {} fn main() {}").is_err());
+
+        let mut host = Host::new();
+        host.register("origin", "||: Pos", |_| Ok(Value::None))
+            .unwrap();
+        let errors = host.compile("fn main() { origin(); }").unwrap_err();
+        let rendered = errors.to_string();
+        assert!(rendered.starts_with("error: unknown type `Pos`"));
+        assert!(rendered.contains(" --> <signature of `origin`>:1:1"));
+        assert!(rendered.contains("in the signature of host function `origin`"));
+        assert!(host
+            .compile("struct Pos { x: int } fn main() { let p: Pos = origin(); }")
+            .is_ok());
+
+        let mut host = Host::new();
+        assert!(host.register("f", "int", |_| Ok(Value::Void)).is_err());
+        assert!(host
+            .register("f", "|int: int", |_| Ok(Value::Void))
+            .is_err());
+        assert!(host
+            .register("f", "|int|: int extra", |_| Ok(Value::Void))
+            .is_err());
+    }
+}
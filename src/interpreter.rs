@@ -0,0 +1,1118 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::diagnostic::Diagnostic;
+use crate::host::Host;
+use crate::types::{
+    Definition, Expression, ExpressionKind, Function, Literal, Op, Param, Span, Spanned, Statement,
+    StatementKind, Struct, Type, AST,
+};
+
+/// A runtime value produced by evaluating an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Double(f64),
+    Str(String),
+    Bool(bool),
+    /// A struct instance. The name is `None` for a `{ field = expr }` literal whose struct
+    /// has not been inferred from context yet. Fields follow the struct's declaration order.
+    Struct(Option<String>, Vec<(String, Value)>),
+    /// A reference to a named function.
+    Function(String),
+    Closure(Arc<Closure>),
+    /// The missing value of an optional. A present value of type `T?` is just the `T`.
+    None,
+    Void,
+}
+
+/// An anonymous function together with the values it captured when it was created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closure {
+    params: Vec<Param>,
+    return_type: Option<Spanned<Type>>,
+    body: Statement,
+    env: HashMap<String, Value>,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Double(n) => write!(f, "{}", n),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Struct(name, fields) => {
+                if let Some(name) = name {
+                    write!(f, "{} ", name)?;
+                }
+                write!(f, "{{")?;
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    let sep = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}: {}", sep, field, value)?;
+                }
+                write!(f, " }}")
+            }
+            Value::Function(name) => write!(f, "fn {}", name),
+            Value::Closure(closure) => {
+                let params: Vec<String> = closure
+                    .params
+                    .iter()
+                    .map(|(_, t)| t.node.to_string())
+                    .collect();
+                let return_type = closure
+                    .return_type
+                    .as_ref()
+                    .map_or(&Type::Void, |t| &t.node);
+                write!(f, "|{}|: {}", params.join(", "), return_type)
+            }
+            Value::None => write!(f, "none"),
+            Value::Void => write!(f, "void"),
+        }
+    }
+}
+
+impl Value {
+    /// Whether the value can be passed where a `t` is expected.
+    pub fn has_type(&self, t: &Type) -> bool {
+        match (self, t) {
+            (Value::Int(_), Type::Int)
+            | (Value::Double(_), Type::Double)
+            | (Value::Str(_), Type::String)
+            | (Value::Bool(_), Type::Bool)
+            | (Value::Void, Type::Void)
+            | (Value::None, Type::Optional(_))
+            | (Value::Function(_) | Value::Closure(_), Type::Function(..)) => true,
+            // An unnamed struct is checked against the fields of `t` when it is passed.
+            (Value::Struct(name, _), Type::Struct(t)) => name.as_ref().is_none_or(|n| n == t),
+            (value, Type::Optional(t)) => value.has_type(t),
+            _ => false,
+        }
+    }
+
+    /// The bytes a copy of the value allocates: the bytes of its strings and
+    /// `size_of::<Value>()` for each struct field. Closures are shared, not copied.
+    fn allocation_size(&self) -> usize {
+        match self {
+            Value::Str(s) => s.len(),
+            Value::Struct(_, fields) => fields
+                .iter()
+                .map(|(_, value)| size_of::<Value>() + value.allocation_size())
+                .sum(),
+            _ => 0,
+        }
+    }
+}
+
+/// What happened after executing a statement.
+enum Flow {
+    Next,
+    Return(Value),
+}
+
+/// How much fuel a program gets by default: enough for a few seconds of work.
+pub const DEFAULT_FUEL: u64 = 100_000_000;
+
+/// How deeply calls nest by default.
+pub const DEFAULT_CALL_DEPTH: usize = 10_000;
+
+/// How many bytes a program may allocate over a run by default.
+pub const DEFAULT_ALLOCATIONS: usize = 1 << 30;
+
+/// When less than this much native stack is left, a call continues on a new stack segment of
+/// `STACK_SEGMENT` bytes. The interpreter recurses on the native stack, so this is what lets
+/// calls nest `DEFAULT_CALL_DEPTH` deep on any thread.
+const STACK_RED_ZONE: usize = 1 << 20;
+const STACK_SEGMENT: usize = 8 << 20;
+
+/// The resources a program may use while it runs. `None` means no limit.
+///
+/// The defaults are `DEFAULT_FUEL`, `DEFAULT_CALL_DEPTH` and `DEFAULT_ALLOCATIONS`, so a
+/// program that never stops is stopped after a while rather than blocking its caller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Evaluating an expression or executing a statement uses one unit of fuel.
+    pub fuel: Option<u64>,
+    /// How many calls may be active at once.
+    pub call_depth: Option<usize>,
+    /// How many bytes the program may allocate in total over the run. This is an allocation
+    /// budget, not a cap on live memory: freed values are never given back, so a loop that
+    /// builds and drops strings runs out of it too. Counted are the bytes of every string
+    /// built or copied and `size_of::<Value>()` for each struct field and captured variable
+    /// created or copied.
+    pub allocations: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            fuel: Some(DEFAULT_FUEL),
+            call_depth: Some(DEFAULT_CALL_DEPTH),
+            allocations: Some(DEFAULT_ALLOCATIONS),
+        }
+    }
+}
+
+/// An error raised while running a program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    /// The innermost expression or statement being run when the error happened. Only errors
+    /// from calling a function from outside the program, e.g. with the wrong arguments, have
+    /// none.
+    pub span: Option<Span>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    /// The program did something invalid, such as dividing by zero.
+    Failure(String),
+    /// The program used all of `Limits::fuel`.
+    OutOfFuel(u64),
+    /// A call would nest deeper than `Limits::call_depth`.
+    CallDepthExceeded(usize),
+    /// An allocation would exceed `Limits::allocations`.
+    AllocationsExceeded(usize),
+}
+
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorKind) -> RuntimeError {
+        RuntimeError { kind, span: None }
+    }
+
+    /// Whether the program was stopped by one of its `Limits` rather than failing itself.
+    pub fn is_limit(&self) -> bool {
+        !matches!(self.kind, RuntimeErrorKind::Failure(_))
+    }
+
+    /// The error as a diagnostic pointing at where it happened, if it has a span.
+    pub fn diagnostic(&self) -> Option<Diagnostic> {
+        let diagnostic = Diagnostic::error(self.span?, self.kind.to_string());
+        Some(match self.kind {
+            RuntimeErrorKind::Failure(_) => diagnostic,
+            RuntimeErrorKind::OutOfFuel(_) => diagnostic
+                .with_label("while running this".to_string())
+                .with_note("the program may loop forever".to_string()),
+            RuntimeErrorKind::CallDepthExceeded(_) => diagnostic
+                .with_label("while making this call".to_string())
+                .with_note("the program may recurse forever".to_string()),
+            RuntimeErrorKind::AllocationsExceeded(_) => {
+                diagnostic.with_label("while allocating this".to_string())
+            }
+        })
+    }
+
+    /// Locate an error raised inside `span`, unless something inside it already located it.
+    fn at(mut self, span: Span) -> RuntimeError {
+        self.span.get_or_insert(span);
+        self
+    }
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::Failure(msg) => write!(f, "{}", msg),
+            RuntimeErrorKind::OutOfFuel(fuel) => write!(f, "ran out of fuel after {} steps", fuel),
+            RuntimeErrorKind::CallDepthExceeded(depth) => {
+                write!(f, "calls nested deeper than {} levels", depth)
+            }
+            RuntimeErrorKind::AllocationsExceeded(bytes) => {
+                write!(f, "allocated more than {} bytes", bytes)
+            }
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Runtime error: {}", self.kind)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Tree-walking interpreter over a parsed `AST`.
+pub struct Interpreter {
+    functions: HashMap<String, Function>,
+    host: Host,
+    structs: HashMap<String, Struct>,
+    global_defs: Vec<(String, Option<Type>, Expression)>,
+    globals: HashMap<String, Value>,
+    /// Whether `globals` holds every global, so that calls do not initialise them again.
+    initialized: bool,
+    /// Local scopes of the function currently executing.
+    scopes: Vec<HashMap<String, Value>>,
+    limits: Limits,
+    fuel_used: u64,
+    call_depth: usize,
+    allocated: usize,
+}
+
+impl Interpreter {
+    pub fn new(ast: &AST) -> Interpreter {
+        let mut functions = HashMap::new();
+        let mut structs = HashMap::new();
+        let mut global_defs = vec![];
+        for def in &ast.definitions {
+            match def {
+                Definition::FnDef(function) => {
+                    functions.insert(function.name.clone(), function.clone());
+                }
+                Definition::StructDef(s) => {
+                    structs.insert(s.name.clone(), s.clone());
+                }
+                Definition::GlobalDef(name, t, expr, ..) => {
+                    let t = t.as_ref().map(|t| t.node.clone());
+                    global_defs.push((name.node.clone(), t, expr.clone()));
+                }
+            }
+        }
+
+        Interpreter {
+            functions,
+            host: Host::new(),
+            structs,
+            global_defs,
+            globals: HashMap::new(),
+            initialized: false,
+            scopes: vec![],
+            limits: Limits::default(),
+            fuel_used: 0,
+            call_depth: 0,
+            allocated: 0,
+        }
+    }
+
+    /// Make the functions of `host` callable from the program.
+    pub fn with_host(mut self, host: &Host) -> Interpreter {
+        self.host = host.clone();
+        self
+    }
+
+    /// Start from globals initialised before, e.g. by another interpreter's
+    /// `initialize_globals`, instead of running their initializers.
+    pub fn with_globals(mut self, globals: HashMap<String, Value>) -> Interpreter {
+        self.globals = globals;
+        self.initialized = true;
+        self
+    }
+
+    /// Stop the program with a `RuntimeError` when it goes over `limits`. Each
+    /// `call_function` starts with the whole budget again.
+    pub fn with_limits(mut self, limits: Limits) -> Interpreter {
+        self.limits = limits;
+        self
+    }
+
+    /// Call `fn main` and return its exit code, initialising globals first if they are not
+    /// yet. A `void` main exits with 0.
+    pub fn run(&mut self) -> Result<i64, RuntimeError> {
+        match self.call_function("main", vec![])? {
+            Value::Int(n) => Ok(n),
+            Value::Void => Ok(0),
+            v => Err(self.error(format!("`main` must return an int, got {}", v))),
+        }
+    }
+
+    /// Call the function `name` with `args`, initialising globals first if they are not yet.
+    /// Later calls see the globals as earlier calls left them. Unlike calls within the
+    /// program, the arguments have not been type checked.
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let Some(function) = self.functions.get(name).cloned() else {
+            return Err(self.error(format!("no `{}` function defined", name)));
+        };
+        for ((param, t), arg) in function.params.iter().zip(&args) {
+            if !arg.has_type(&t.node) {
+                return Err(self.error(format!(
+                    "expected `{}` for `{}` in `{}`, got {}",
+                    t.node, param.node, name, arg
+                )));
+            }
+        }
+
+        self.fuel_used = 0;
+        self.allocated = 0;
+        if !self.initialized {
+            self.run_initializers()?;
+        }
+        self.call(name, args)
+    }
+
+    /// Initialise globals in declaration order, within a budget of their own.
+    pub fn initialize_globals(&mut self) -> Result<(), RuntimeError> {
+        self.fuel_used = 0;
+        self.allocated = 0;
+        self.run_initializers()
+    }
+
+    /// Initialise the global `name` alone, within a budget of its own, keeping the values of
+    /// the other globals. The initializer sees the globals as they are now.
+    pub fn initialize_global(&mut self, name: &str) -> Result<(), RuntimeError> {
+        self.fuel_used = 0;
+        self.allocated = 0;
+        let Some((_, t, expr)) = self.global_defs.iter().find(|(n, _, _)| n == name).cloned()
+        else {
+            return Err(self.error(format!("no global named `{}`", name)));
+        };
+        let value = self.initialize(&expr, t.as_ref())?;
+        self.globals.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// The globals and their current values.
+    pub fn globals(&self) -> &HashMap<String, Value> {
+        &self.globals
+    }
+
+    fn run_initializers(&mut self) -> Result<(), RuntimeError> {
+        self.globals.clear();
+        for (name, t, expr) in self.global_defs.clone() {
+            if self.globals.contains_key(&name) {
+                return Err(self.error(format!("global `{}` is defined more than once", name)));
+            }
+            let value = self.initialize(&expr, t.as_ref())?;
+            self.globals.insert(name, value);
+        }
+        self.initialized = true;
+        Ok(())
+    }
+
+    /// The value of a global initialised with `expr`, converted to its annotation `t`.
+    fn initialize(&mut self, expr: &Expression, t: Option<&Type>) -> Result<Value, RuntimeError> {
+        let value = self.evaluate(expr)?;
+        match t {
+            Some(t) => self.coerce(value, t),
+            None => Ok(value),
+        }
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let function = match self.functions.get(name) {
+            Some(function) => function.clone(),
+            None => return self.call_host(name, args),
+        };
+
+        self.invoke(
+            name,
+            &function.params,
+            function.return_type.as_ref().map(|t| &t.node),
+            &function.body,
+            HashMap::new(),
+            args,
+        )
+    }
+
+    fn call_closure(&mut self, closure: &Closure, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        self.invoke(
+            "closure",
+            &closure.params,
+            Some(
+                closure
+                    .return_type
+                    .as_ref()
+                    .map_or(&Type::Void, |t| &t.node),
+            ),
+            &closure.body,
+            closure.env.clone(),
+            args,
+        )
+    }
+
+    /// Run `body` in a fresh scope holding `env` and the arguments bound to `params`.
+    fn invoke(
+        &mut self,
+        name: &str,
+        params: &[Param],
+        return_type: Option<&Type>,
+        body: &Statement,
+        mut env: HashMap<String, Value>,
+        args: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        if params.len() != args.len() {
+            return Err(self.error(format!(
+                "`{}` takes {} argument(s) but {} were given",
+                name,
+                params.len(),
+                args.len()
+            )));
+        }
+
+        for ((param, t), arg) in params.iter().zip(args) {
+            env.insert(param.node.clone(), self.coerce(arg, &t.node)?);
+        }
+
+        if let Some(max) = self.limits.call_depth {
+            if self.call_depth >= max {
+                return Err(RuntimeError::new(RuntimeErrorKind::CallDepthExceeded(max)));
+            }
+        }
+
+        let caller_scopes = std::mem::replace(&mut self.scopes, vec![env]);
+        self.call_depth += 1;
+        let flow = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, || self.execute(body));
+        self.call_depth -= 1;
+        self.scopes = caller_scopes;
+
+        match flow? {
+            Flow::Return(value) => match return_type {
+                Some(t) => self.coerce(value, t),
+                None => Ok(value),
+            },
+            Flow::Next => match return_type {
+                None | Some(Type::Void) => Ok(Value::Void),
+                Some(_) => Err(self.error(format!("`{}` did not return a value", name))),
+            },
+        }
+    }
+
+    /// Call a host function, checking that it returns what its signature promises.
+    fn call_host(&mut self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let Some(function) = self.host.get(name).cloned() else {
+            return self.call_builtin(name, args);
+        };
+
+        let value = (function.function)(args)
+            .map_err(|err| self.error(format!("host function `{}` failed: {}", name, err)))?;
+        if !value.has_type(&function.return_type) {
+            return Err(self.error(format!(
+                "host function `{}` returned {}, expected `{}`",
+                name, value, function.return_type
+            )));
+        }
+        self.coerce(value, &function.return_type)
+    }
+
+    fn call_builtin(&mut self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        match name {
+            "print" => {
+                let s = self.format(args)?;
+                println!("{}", s);
+                Ok(Value::Void)
+            }
+            "format" => {
+                let s = self.format(args)?;
+                self.allocate(s.len())?;
+                Ok(Value::Str(s))
+            }
+            _ => Err(self.error(format!("call to undefined function `{}`", name))),
+        }
+    }
+
+    /// Replace each `{}` in the first argument with the following arguments, in order.
+    fn format(&mut self, args: Vec<Value>) -> Result<String, RuntimeError> {
+        let mut args = args.into_iter();
+        let fmt = match args.next() {
+            Some(Value::Str(s)) => s,
+            _ => return Err(self.error("expected a format string".to_string())),
+        };
+
+        let mut pieces = fmt.split("{}");
+        let mut out = pieces.next().unwrap_or_default().to_string();
+        for piece in pieces {
+            match args.next() {
+                Some(arg) => out.push_str(&arg.to_string()),
+                None => return Err(self.error("missing argument for `{}`".to_string())),
+            }
+            out.push_str(piece);
+        }
+
+        if args.next().is_some() {
+            return Err(self.error("too many arguments for format string".to_string()));
+        }
+
+        Ok(out)
+    }
+
+    fn execute(&mut self, stmt: &Statement) -> Result<Flow, RuntimeError> {
+        self.use_fuel()
+            .and_then(|_| self.execute_kind(stmt))
+            .map_err(|err| err.at(stmt.span))
+    }
+
+    fn execute_kind(&mut self, stmt: &Statement) -> Result<Flow, RuntimeError> {
+        match &stmt.kind {
+            StatementKind::Block(stmts) => {
+                self.scopes.push(HashMap::new());
+                let mut flow = Ok(Flow::Next);
+                for stmt in stmts.iter() {
+                    flow = self.execute(stmt);
+                    if !matches!(flow, Ok(Flow::Next)) {
+                        break;
+                    }
+                }
+                self.scopes.pop();
+                flow
+            }
+            StatementKind::RetStmt(Some(expr)) => Ok(Flow::Return(self.evaluate(expr)?)),
+            StatementKind::RetStmt(None) => Ok(Flow::Return(Value::Void)),
+            StatementKind::DeclStmt(name, t, expr) => {
+                let mut value = self.evaluate(expr)?;
+                if let Some(t) = t {
+                    value = self.coerce(value, &t.node)?;
+                }
+                self.declare(&name.node, value);
+                Ok(Flow::Next)
+            }
+            StatementKind::AssignStmt(target, expr) => {
+                let value = self.evaluate(expr)?;
+                self.assign(target, value)?;
+                Ok(Flow::Next)
+            }
+            StatementKind::ExprStmt(expr) => {
+                self.evaluate(expr)?;
+                Ok(Flow::Next)
+            }
+            StatementKind::IfStmt(cond, then, otherwise) => {
+                if self.condition(cond)? {
+                    self.execute(then)
+                } else if let Some(otherwise) = otherwise {
+                    self.execute(otherwise)
+                } else {
+                    Ok(Flow::Next)
+                }
+            }
+            StatementKind::IfLetStmt(name, expr, then, otherwise) => match self.evaluate(expr)? {
+                Value::None => match otherwise {
+                    Some(otherwise) => self.execute(otherwise),
+                    None => Ok(Flow::Next),
+                },
+                value => {
+                    self.scopes
+                        .push(HashMap::from([(name.node.clone(), value)]));
+                    let flow = self.execute(then);
+                    self.scopes.pop();
+                    flow
+                }
+            },
+            StatementKind::WhileStmt(cond, body) => {
+                while self.condition(cond)? {
+                    if let Flow::Return(value) = self.execute(body)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+                Ok(Flow::Next)
+            }
+            StatementKind::ForStmt(name, iter, body) => {
+                let items: Vec<Value> = match self.evaluate(iter)? {
+                    Value::Str(s) => {
+                        self.allocate(s.len())?;
+                        s.chars().map(|c| Value::Str(c.to_string())).collect()
+                    }
+                    v => return Err(self.error(format!("cannot iterate over {:?}", v))),
+                };
+                for item in items {
+                    self.scopes.push(HashMap::from([(name.node.clone(), item)]));
+                    let flow = self.execute(body);
+                    self.scopes.pop();
+                    if let Flow::Return(value) = flow? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+                Ok(Flow::Next)
+            }
+            StatementKind::EmptyStmt => Ok(Flow::Next),
+        }
+    }
+
+    fn condition(&mut self, cond: &Expression) -> Result<bool, RuntimeError> {
+        match self.evaluate(cond)? {
+            Value::Bool(b) => Ok(b),
+            v => Err(self.error(format!("condition must be a bool, got {:?}", v))),
+        }
+    }
+
+    fn declare(&mut self, name: &str, value: Value) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), value);
+        }
+    }
+
+    fn assign(&mut self, target: &Expression, value: Value) -> Result<(), RuntimeError> {
+        // Walk `a.b.c` down to the variable `a`, collecting the field path `[b, c]`.
+        let mut path = vec![];
+        let mut root = target;
+        while let ExpressionKind::FieldAccess(base, field) = &root.kind {
+            path.push(field);
+            root = base;
+        }
+        path.reverse();
+        let name = match &root.kind {
+            ExpressionKind::VarAccess(name) => name,
+            target => return Err(self.error(format!("cannot assign to {:?}", target))),
+        };
+
+        let slot = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|s| s.get_mut(name))
+            .or_else(|| self.globals.get_mut(name));
+        let mut slot = match slot {
+            Some(slot) => slot,
+            None => return Err(self.error(format!("assignment to undefined variable `{}`", name))),
+        };
+        for field in path {
+            let found = match slot {
+                Value::Struct(_, fields) => fields.iter_mut().find(|(f, _)| f == field),
+                _ => None,
+            };
+            slot = match found {
+                Some((_, value)) => value,
+                None => {
+                    return Err(RuntimeError::new(RuntimeErrorKind::Failure(format!(
+                        "no field `{}` on value",
+                        field
+                    ))))
+                }
+            };
+        }
+        *slot = value;
+
+        Ok(())
+    }
+
+    /// Give an anonymous struct value the struct type it is used as, checking its fields.
+    fn coerce(&mut self, value: Value, t: &Type) -> Result<Value, RuntimeError> {
+        match (value, t) {
+            (Value::Struct(None, fields), Type::Struct(name)) => self.build_struct(name, fields),
+            (value, Type::Optional(t)) => self.coerce(value, t),
+            (value, _) => Ok(value),
+        }
+    }
+
+    /// Build an instance of the struct `name`, ordering the fields as they were declared.
+    fn build_struct(
+        &mut self,
+        name: &str,
+        mut fields: Vec<(String, Value)>,
+    ) -> Result<Value, RuntimeError> {
+        let def = match self.structs.get(name) {
+            Some(def) => def.clone(),
+            None => return Err(self.error(format!("unknown struct `{}`", name))),
+        };
+
+        let mut values = vec![];
+        for (field, t) in &def.fields {
+            let field = &field.node;
+            let value = match fields.iter().position(|(f, _)| f == field) {
+                Some(i) => fields.remove(i).1,
+                None => return Err(self.error(format!("missing field `{}` in `{}`", field, name))),
+            };
+            values.push((field.clone(), self.coerce(value, &t.node)?));
+        }
+        if let Some((field, _)) = fields.first() {
+            return Err(self.error(format!("`{}` has no field `{}`", name, field)));
+        }
+
+        Ok(Value::Struct(Some(name.to_string()), values))
+    }
+
+    fn evaluate(&mut self, expr: &Expression) -> Result<Value, RuntimeError> {
+        self.use_fuel()
+            .and_then(|_| self.evaluate_kind(expr))
+            .map_err(|err| err.at(expr.span))
+    }
+
+    fn evaluate_kind(&mut self, expr: &Expression) -> Result<Value, RuntimeError> {
+        match &expr.kind {
+            ExpressionKind::Literal(literal) => {
+                if let Literal::String(s) = literal {
+                    self.allocate(s.len())?;
+                }
+                Ok(Self::literal(literal))
+            }
+            ExpressionKind::VarAccess(name) => {
+                let value = self.lookup(name)?;
+                self.allocate(value.allocation_size())?;
+                Ok(value)
+            }
+            ExpressionKind::FnCall(callee, args) => {
+                // Calls by name go straight to the function, unless a variable holding a
+                // function shadows it.
+                let callee = match &callee.kind {
+                    ExpressionKind::VarAccess(name) => match self.lookup_variable(name) {
+                        Some(value) => value,
+                        None => Value::Function(name.clone()),
+                    },
+                    _ => self.evaluate(callee)?,
+                };
+                let args = args
+                    .iter()
+                    .map(|arg| self.evaluate(arg))
+                    .collect::<Result<Vec<Value>, RuntimeError>>()?;
+                match callee {
+                    Value::Function(function) => self.call(&function, args),
+                    Value::Closure(closure) => self.call_closure(&closure, args),
+                    v => Err(self.error(format!("{:?} is not a function", v))),
+                }
+            }
+            ExpressionKind::UnaryOp(op, operand) => {
+                let operand = self.evaluate(operand)?;
+                self.unary_op(op, operand)
+            }
+            ExpressionKind::BinaryOp(op, lhs, rhs) => {
+                let lhs = self.evaluate(lhs)?;
+                // `&&`, `||` and `??` only evaluate their right side when it decides the result.
+                match (op, &lhs) {
+                    (Op::And, Value::Bool(false)) | (Op::Or, Value::Bool(true)) => return Ok(lhs),
+                    (Op::Coalesce, Value::None) => return self.evaluate(rhs),
+                    (Op::Coalesce, _) => return Ok(lhs),
+                    _ => {}
+                }
+                let rhs = self.evaluate(rhs)?;
+                self.binary_op(op, lhs, rhs)
+            }
+            ExpressionKind::StructLiteral(name, fields) => {
+                let mut values = vec![];
+                for (field, expr) in fields {
+                    values.push((field.clone(), self.evaluate(expr)?));
+                }
+                self.allocate(values.len() * size_of::<Value>())?;
+                match name {
+                    Some(name) => self.build_struct(name, values),
+                    None => {
+                        // Name the literal if only one struct has exactly these fields,
+                        // otherwise wait for a context to `coerce` it.
+                        let names: Vec<&str> = values.iter().map(|(f, _)| f.as_str()).collect();
+                        let mut candidates = self.structs.values().filter(|s| s.has_fields(&names));
+                        match (candidates.next(), candidates.next()) {
+                            (Some(s), None) => {
+                                let name = s.name.clone();
+                                self.build_struct(&name, values)
+                            }
+                            _ => Ok(Value::Struct(None, values)),
+                        }
+                    }
+                }
+            }
+            ExpressionKind::FieldAccess(base, field) => match self.evaluate(base)? {
+                Value::Struct(_, fields) => match fields.into_iter().find(|(f, _)| f == field) {
+                    Some((_, value)) => Ok(value),
+                    None => Err(self.error(format!("no field `{}` on struct", field))),
+                },
+                v => Err(self.error(format!("cannot access field `{}` on {:?}", field, v))),
+            },
+            ExpressionKind::AnonFnDef(f) => {
+                let mut env = HashMap::new();
+                for name in &f.captures {
+                    let value = self.lookup(name)?;
+                    self.allocate(size_of::<Value>() + value.allocation_size())?;
+                    env.insert(name.clone(), value);
+                }
+                Ok(Value::Closure(Arc::new(Closure {
+                    params: f.params.clone(),
+                    return_type: f.return_type.clone(),
+                    body: *f.body.clone(),
+                    env,
+                })))
+            }
+        }
+    }
+
+    fn literal(literal: &Literal) -> Value {
+        match literal {
+            Literal::Int(n) => Value::Int(*n),
+            Literal::Double(n) => Value::Double(*n),
+            Literal::String(s) => Value::Str(s.clone()),
+            Literal::Bool(b) => Value::Bool(*b),
+            Literal::None => Value::None,
+        }
+    }
+
+    fn lookup(&mut self, name: &str) -> Result<Value, RuntimeError> {
+        if let Some(value) = self.lookup_variable(name) {
+            return Ok(value);
+        }
+        if self.functions.contains_key(name) || self.host.get(name).is_some() {
+            return Ok(Value::Function(name.to_string()));
+        }
+
+        Err(self.error(format!("use of undefined variable `{}`", name)))
+    }
+
+    fn lookup_variable(&self, name: &str) -> Option<Value> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .or_else(|| self.globals.get(name))
+            .cloned()
+    }
+
+    fn unary_op(&mut self, op: &Op, operand: Value) -> Result<Value, RuntimeError> {
+        match (op, &operand) {
+            (Op::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+            (Op::Sub, Value::Int(n)) => n
+                .checked_neg()
+                .map(Value::Int)
+                .ok_or_else(|| self.error("integer overflow".to_string())),
+            (Op::Sub, Value::Double(n)) => Ok(Value::Double(-n)),
+            _ => Err(self.error(format!("unsupported operand for {:?}: {:?}", op, operand))),
+        }
+    }
+
+    fn binary_op(&mut self, op: &Op, lhs: Value, rhs: Value) -> Result<Value, RuntimeError> {
+        let value = match (op, &lhs, &rhs) {
+            (Op::Add, Value::Int(a), Value::Int(b)) => a.checked_add(*b).map(Value::Int),
+            (Op::Sub, Value::Int(a), Value::Int(b)) => a.checked_sub(*b).map(Value::Int),
+            (Op::Mul, Value::Int(a), Value::Int(b)) => a.checked_mul(*b).map(Value::Int),
+            (Op::Div | Op::Mod, Value::Int(_), Value::Int(0)) => {
+                return Err(self.error("division by zero".to_string()))
+            }
+            (Op::Div, Value::Int(a), Value::Int(b)) => a.checked_div(*b).map(Value::Int),
+            (Op::Mod, Value::Int(a), Value::Int(b)) => a.checked_rem(*b).map(Value::Int),
+            (Op::Add, Value::Double(a), Value::Double(b)) => Some(Value::Double(a + b)),
+            (Op::Sub, Value::Double(a), Value::Double(b)) => Some(Value::Double(a - b)),
+            (Op::Mul, Value::Double(a), Value::Double(b)) => Some(Value::Double(a * b)),
+            (Op::Div, Value::Double(a), Value::Double(b)) => Some(Value::Double(a / b)),
+            (Op::Mod, Value::Double(a), Value::Double(b)) => Some(Value::Double(a % b)),
+            (Op::Add, Value::Str(a), Value::Str(b)) => {
+                self.allocate(a.len() + b.len())?;
+                Some(Value::Str(format!("{}{}", a, b)))
+            }
+            (Op::BitAnd, Value::Int(a), Value::Int(b)) => Some(Value::Int(a & b)),
+            (Op::BitOr, Value::Int(a), Value::Int(b)) => Some(Value::Int(a | b)),
+            (Op::BitXor, Value::Int(a), Value::Int(b)) => Some(Value::Int(a ^ b)),
+            (Op::Shl | Op::Shr, Value::Int(_), Value::Int(b)) if !(0..64).contains(b) => {
+                return Err(self.error(format!("cannot shift by {}", b)))
+            }
+            (Op::Shl, Value::Int(a), Value::Int(b)) => Some(Value::Int(a << b)),
+            (Op::Shr, Value::Int(a), Value::Int(b)) => Some(Value::Int(a >> b)),
+            (Op::And | Op::BitAnd, Value::Bool(a), Value::Bool(b)) => Some(Value::Bool(*a && *b)),
+            (Op::Or | Op::BitOr, Value::Bool(a), Value::Bool(b)) => Some(Value::Bool(*a || *b)),
+            (Op::BitXor, Value::Bool(a), Value::Bool(b)) => Some(Value::Bool(a ^ b)),
+            (Op::Eq, _, _) => Some(Value::Bool(lhs == rhs)),
+            (Op::Neq, _, _) => Some(Value::Bool(lhs != rhs)),
+            (Op::Lt | Op::Gt | Op::Lte | Op::Gte, _, _) => {
+                let ordering = match (&lhs, &rhs) {
+                    (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+                    (Value::Double(a), Value::Double(b)) => a.partial_cmp(b),
+                    (Value::Str(a), Value::Str(b)) => a.partial_cmp(b),
+                    _ => None,
+                };
+                match ordering {
+                    Some(ordering) => Some(Value::Bool(match op {
+                        Op::Lt => ordering.is_lt(),
+                        Op::Gt => ordering.is_gt(),
+                        Op::Lte => ordering.is_le(),
+                        _ => ordering.is_ge(),
+                    })),
+                    None => return Err(self.operand_error(op, &lhs, &rhs)),
+                }
+            }
+            _ => return Err(self.operand_error(op, &lhs, &rhs)),
+        };
+
+        value.ok_or_else(|| self.error("integer overflow".to_string()))
+    }
+
+    fn use_fuel(&mut self) -> Result<(), RuntimeError> {
+        self.fuel_used += 1;
+        match self.limits.fuel {
+            Some(fuel) if self.fuel_used > fuel => {
+                Err(RuntimeError::new(RuntimeErrorKind::OutOfFuel(fuel)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Count `bytes` against the allocation budget.
+    fn allocate(&mut self, bytes: usize) -> Result<(), RuntimeError> {
+        self.allocated = self.allocated.saturating_add(bytes);
+        match self.limits.allocations {
+            Some(budget) if self.allocated > budget => Err(RuntimeError::new(
+                RuntimeErrorKind::AllocationsExceeded(budget),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn operand_error(&self, op: &Op, lhs: &Value, rhs: &Value) -> RuntimeError {
+        self.error(format!(
+            "unsupported operands for {:?}: {:?} and {:?}",
+            op, lhs, rhs
+        ))
+    }
+
+    fn error(&self, msg: String) -> RuntimeError {
+        RuntimeError::new(RuntimeErrorKind::Failure(msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::tokenizer::Tokenizer;
+
+    fn run(source: &str) -> Result<i64, RuntimeError> {
+        run_with(source, Limits::default())
+    }
+
+    fn run_with(source: &str, limits: Limits) -> Result<i64, RuntimeError> {
+        let tokens = Tokenizer::new(0).tokenize(source).unwrap();
+        let ast = Parser::new(tokens).parse().unwrap();
+        Interpreter::new(&ast).with_limits(limits).run()
+    }
+
+    #[test]
+    fn main_return_value_is_exit_code() {
+        assert_eq!(run("fn main() -> int { return 6 * 7; }"), Ok(42));
+        assert_eq!(run("fn main() {}"), Ok(0));
+    }
+
+    #[test]
+    fn call_binds_parameters() {
+        let source = "
+            fn sub(a: int, b: int) -> int { return a - b; }
+            fn main() -> int { return sub(5, 3); }
+        ";
+        assert_eq!(run(source), Ok(2));
+    }
+
+    #[test]
+    fn callee_cannot_see_caller_locals() {
+        let source = "
+            fn f() -> int { return x; }
+            fn main() -> int { let x = 1; return f(); }
+        ";
+        assert!(run(source).is_err());
+    }
+
+    #[test]
+    fn missing_main_is_an_error() {
+        assert!(run("fn f() {}").is_err());
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert!(run("fn main() -> int { return 1 / 0; }").is_err());
+    }
+
+    #[test]
+    fn runtime_errors_point_at_the_innermost_expression() {
+        let source = "fn main() -> int { let x = 0; return 2 + 1 / x; }";
+        let err = run(source).unwrap_err();
+        assert_eq!(
+            err.kind,
+            RuntimeErrorKind::Failure("division by zero".to_string())
+        );
+        assert_eq!(err.span.map(|s| &source[s.start..s.end]), Some("1 / x"));
+        assert!(!err.is_limit());
+    }
+
+    #[test]
+    fn limits_stop_programs() {
+        let limits = |fuel, call_depth, allocations| Limits {
+            fuel,
+            call_depth,
+            allocations,
+        };
+
+        let spin = "fn main() -> int { let i = 0; while true { i += 1; } return i; }";
+        let err = run_with(spin, limits(Some(1000), None, None)).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::OutOfFuel(1000));
+        assert!(err.is_limit() && err.span.is_some());
+        let count = "fn main() -> int { let i = 0; while i < 10 { i += 1; } return i; }";
+        assert_eq!(run_with(count, limits(Some(1000), None, None)), Ok(10));
+
+        let source = "
+            fn depth(n: int) -> int {
+                if n == 0 { return 0; }
+                return 1 + depth(n - 1);
+            }
+            fn main() -> int { return depth(10); }
+        ";
+        let err = run_with(source, limits(None, Some(5), None)).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::CallDepthExceeded(5));
+        let call = err.span.map(|s| &source[s.start..s.end]);
+        assert_eq!(call, Some("depth(n - 1)"));
+        assert_eq!(run_with(source, limits(None, Some(12), None)), Ok(10));
+
+        // The default depth does not depend on the stack of the thread running the program.
+        let deep = source.replace("depth(10)", "depth(9000)");
+        let deep = std::thread::spawn(move || run_with(&deep, Limits::default()));
+        assert_eq!(deep.join().unwrap(), Ok(9000));
+
+        let source = "
+            fn main() -> int {
+                let s = \"\";
+                for c in \"abcdefghij\" { s = s + c + c; }
+                return 0;
+            }
+        ";
+        let err = run_with(source, limits(None, None, Some(64))).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::AllocationsExceeded(64));
+        assert_eq!(run_with(source, limits(None, None, Some(1024))), Ok(0));
+
+        // Copies count against the budget too, even though nothing stays alive.
+        let source = "
+            fn main() -> int {
+                let s = \"abcdefghij\";
+                let t = s;
+                let f = ||: str { return s; };
+                return 0;
+            }
+        ";
+        let budget = 30 + size_of::<Value>();
+        let err = run_with(source, limits(None, None, Some(budget - 1))).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::AllocationsExceeded(budget - 1));
+        assert_eq!(run_with(source, limits(None, None, Some(budget))), Ok(0));
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        let source = "
+            fn fail() -> bool { return 1 / 0 == 0; }
+            fn main() -> int {
+                if 1 == 2 && fail() { return 1; }
+                if 1 == 1 || fail() { return 2; }
+                return 3;
+            }
+        ";
+        assert_eq!(run(source), Ok(2));
+    }
+
+    #[test]
+    fn optionals() {
+        let source = "
+            struct Pos { x: int }
+            fn find(n: int) -> Pos? {
+                if n < 0 { return none; }
+                return { x = n };
+            }
+            fn fail() -> int { return 1 / 0; }
+            fn main() -> int {
+                let total = 0;
+                if let p = find(-1) { total += 100; } else { total += 1; }
+                if let p = find(20) { total += p.x; }
+                let n: int? = none;
+                total += n ?? 10;
+                n = 5;
+                total += n ?? fail();
+                if n != none && false == !true { total += 1; }
+                return total;
+            }
+        ";
+        assert_eq!(run(source), Ok(37));
+    }
+
+    #[test]
+    fn bitwise_and_compound_assignment() {
+        let source = "
+            fn main() -> int {
+                let x = (0b1100 & 0b1010 | 1) ^ 0xF0;
+                x += 3 << 2;
+                x -= 256 >> 4;
+                x *= 2;
+                x /= 4;
+                x %= 100;
+                return x;
+            }
+        ";
+        assert_eq!(run(source), Ok(22));
+        assert!(run("fn main() -> int { return 1 << 64; }").is_err());
+    }
+}
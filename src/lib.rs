@@ -0,0 +1,354 @@
+//! samlang as a library. Compile source text into a type checked `Program` and run it from
+//! Rust, passing `Value`s in and out.
+//!
+//! ```
+//! let program = slang::compile("fn add(a: int, b: int) -> int { return a + b; }").unwrap();
+//! let sum = program.call("add", vec![slang::Value::Int(2), slang::Value::Int(3)]);
+//! assert_eq!(sum, Ok(slang::Value::Int(5)));
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::OnceLock;
+
+use checker::Checker;
+use interpreter::Interpreter;
+use parser::Parser;
+use tokenizer::Tokenizer;
+use types::{Span, Type, AST};
+
+pub mod analysis;
+pub mod bundle;
+pub mod captures;
+pub mod checker;
+pub mod diagnostic;
+pub mod formatter;
+pub mod host;
+pub mod interpreter;
+pub mod lsp;
+pub mod parser;
+pub mod repl;
+pub mod source;
+pub mod tokenizer;
+pub mod types;
+
+pub use diagnostic::Diagnostic;
+pub use host::Host;
+pub use interpreter::{Limits, RuntimeError, RuntimeErrorKind, Value};
+pub use source::SourceMap;
+
+/// Compile a single source file.
+pub fn compile(source: &str) -> Result<Program, Diagnostics> {
+    let mut sources = SourceMap::new();
+    sources.add("main.sk".to_string(), source.to_string());
+    Program::compile(sources)
+}
+
+/// Tokenize and parse every file in `sources` into one syntax tree.
+pub fn parse(sources: &SourceMap) -> Result<AST, Vec<Diagnostic>> {
+    let mut ast = AST::new();
+    let mut diagnostics = vec![];
+    for (file_id, file) in sources.files().iter().enumerate() {
+        let parsed = Tokenizer::new(file_id)
+            .tokenize(&file.text)
+            .and_then(|tokens| Parser::new(tokens).parse());
+        match parsed {
+            Ok(file_ast) => ast.definitions.extend(file_ast.definitions),
+            Err(errors) => diagnostics.extend(errors),
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    Ok(ast)
+}
+
+/// A type checked program, ready to run.
+#[derive(Debug, Clone)]
+pub struct Program {
+    ast: AST,
+    sources: SourceMap,
+    host: Host,
+    limits: Limits,
+    /// The values of the globals once their initializers have run, or how they failed.
+    globals: OnceLock<Result<HashMap<String, Value>, RuntimeError>>,
+}
+
+impl Program {
+    /// Compile the files in `sources` together as one program.
+    pub fn compile(sources: SourceMap) -> Result<Program, Diagnostics> {
+        Program::compile_with_host(sources, Host::new())
+    }
+
+    /// Compile the files in `sources` together as one program to be run, which must define
+    /// `fn main`.
+    pub fn compile_main(sources: SourceMap) -> Result<Program, Diagnostics> {
+        Program::compile_checked(sources, Host::new(), true)
+    }
+
+    /// Compile the files in `sources` together as one program that may call the functions of
+    /// `host`.
+    pub fn compile_with_host(sources: SourceMap, host: Host) -> Result<Program, Diagnostics> {
+        Program::compile_checked(sources, host, false)
+    }
+
+    fn compile_checked(
+        sources: SourceMap,
+        host: Host,
+        require_main: bool,
+    ) -> Result<Program, Diagnostics> {
+        let ast = match parse(&sources) {
+            Ok(ast) => ast,
+            Err(diagnostics) => return Err(Diagnostics::new(sources, diagnostics)),
+        };
+        // Each host signature gets a file of its own, so that errors in it can be shown.
+        let mut signatures = sources.clone();
+        let mut names = vec![];
+        let mut checker = Checker::new();
+        if require_main {
+            checker.require_main();
+        }
+        for (name, function) in host.functions() {
+            let params = function.params.clone();
+            let return_type = function.return_type.clone();
+            let text = Type::Function(params.clone(), Box::new(return_type.clone())).to_string();
+            let span = Span {
+                file_id: sources.files().len() + names.len(),
+                start: 0,
+                end: text.len(),
+            };
+            signatures.add(format!("<signature of `{}`>", name), text);
+            names.push(name);
+            checker.declare_function(name, params, return_type, span);
+        }
+        if let Err(errors) = checker.check(&ast) {
+            let diagnostics = errors
+                .into_iter()
+                .map(
+                    |err| match err.span.file_id.checked_sub(sources.files().len()) {
+                        Some(i) => Diagnostic::from(err)
+                            .with_note(format!("in the signature of host function `{}`", names[i])),
+                        None => Diagnostic::from(err),
+                    },
+                )
+                .collect();
+            return Err(Diagnostics::new(signatures, diagnostics));
+        }
+
+        Ok(Program {
+            ast,
+            sources,
+            host,
+            limits: Limits::default(),
+            globals: OnceLock::new(),
+        })
+    }
+
+    /// Stop runs of the program that go over `limits` with a `RuntimeError`.
+    /// # Example:
+    /// ```
+    /// let program = slang::compile("fn main() { while true {} }").unwrap();
+    /// let limits = slang::Limits { fuel: Some(1000), ..Default::default() };
+    /// let err = program.with_limits(limits).run().unwrap_err();
+    /// assert_eq!(err.kind, slang::RuntimeErrorKind::OutOfFuel(1000));
+    /// ```
+    pub fn with_limits(mut self, limits: Limits) -> Program {
+        self.limits = limits;
+        self.globals = OnceLock::new();
+        self
+    }
+
+    /// Run `fn main` and return what it returns, `Value::Void` if it has no return type.
+    ///
+    /// Without `with_limits`, the program runs within `Limits::default()`, so one that loops
+    /// forever runs out of fuel after a few seconds of work.
+    pub fn run(&self) -> Result<Value, RuntimeError> {
+        self.call("main", vec![])
+    }
+
+    /// Call the function `name` with `args`.
+    ///
+    /// The globals are initialised once, by the first call, and every call starts from a copy
+    /// of those values. Assignments to globals in one call are not seen by the next, and an
+    /// initializer that fails makes every call fail the same way.
+    pub fn call(&self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let globals = self.globals.get_or_init(|| {
+            let mut interpreter = self.interpreter();
+            interpreter.initialize_globals()?;
+            Ok(interpreter.globals().clone())
+        });
+        self.interpreter()
+            .with_globals(globals.clone()?)
+            .call_function(name, args)
+    }
+
+    fn interpreter(&self) -> Interpreter {
+        Interpreter::new(&self.ast)
+            .with_host(&self.host)
+            .with_limits(self.limits)
+    }
+
+    pub fn ast(&self) -> &AST {
+        &self.ast
+    }
+
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+}
+
+/// Everything wrong with a program that failed to compile, along with the sources the
+/// diagnostics point into.
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    sources: SourceMap,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new(sources: SourceMap, diagnostics: Vec<Diagnostic>) -> Diagnostics {
+        Diagnostics {
+            sources,
+            diagnostics,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter()
+    }
+
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+}
+
+/// Renders every diagnostic with the source lines it points at.
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic.render(&self.sources))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compiles_and_runs_programs() {
+        let program = compile(
+            "
+            struct Pos { x: int, y: int }
+            let origin: Pos = { x = 1, y = 2 };
+            fn shift(p: Pos, by: int?) -> Pos {
+                return { x = p.x + (by ?? 0), y = p.y };
+            }
+            fn main() -> int { return shift(origin, 3).x; }
+            ",
+        )
+        .unwrap();
+        assert_eq!(program.run(), Ok(Value::Int(4)));
+
+        let pos = Value::Struct(
+            None,
+            vec![
+                ("y".to_string(), Value::Int(5)),
+                ("x".to_string(), Value::Int(0)),
+            ],
+        );
+        assert_eq!(
+            program.call("shift", vec![pos, Value::None]),
+            Ok(Value::Struct(
+                Some("Pos".to_string()),
+                vec![
+                    ("x".to_string(), Value::Int(0)),
+                    ("y".to_string(), Value::Int(5))
+                ]
+            ))
+        );
+        assert!(program
+            .call("shift", vec![Value::Int(1), Value::None])
+            .is_err());
+        assert!(program.call("missing", vec![]).is_err());
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn programs_can_be_shared_between_threads() {
+        assert_send_sync::<Program>();
+        assert_send_sync::<Value>();
+        assert_send_sync::<Host>();
+
+        let mut host = Host::new();
+        host.register("twice", "|int|: int", |args| match args[0] {
+            Value::Int(n) => Ok(Value::Int(n * 2)),
+            _ => Err("expected an int".to_string()),
+        })
+        .unwrap();
+        let program = host
+            .compile("fn f(n: int) -> int { let g = |x: int|: int { return twice(x) + n; }; return g(n); }")
+            .unwrap();
+        let program = &program;
+        let results: Vec<Value> = std::thread::scope(|s| {
+            let workers: Vec<_> = (0..4)
+                .map(|n| s.spawn(move || program.call("f", vec![Value::Int(n)]).unwrap()))
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+        assert_eq!(results, [0, 3, 6, 9].map(Value::Int));
+    }
+
+    #[test]
+    fn globals_are_initialised_once() {
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut host = Host::new();
+        let counter = calls.clone();
+        host.register("tick", "||: int", move |_| {
+            let n = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(Value::Int(n as i64))
+        })
+        .unwrap();
+        let program = host
+            .compile("let n = tick(); fn bump() -> int { n = n + 10; return n; }")
+            .unwrap();
+        assert_eq!(program.call("bump", vec![]), Ok(Value::Int(10)));
+        assert_eq!(program.call("bump", vec![]), Ok(Value::Int(10)));
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn programs_to_run_need_main() {
+        let mut sources = SourceMap::new();
+        sources.add("a.sk".to_string(), "fn f() {}".to_string());
+        sources.add("b.sk".to_string(), "fn g() {}\n\n".to_string());
+        assert!(Program::compile(sources.clone()).is_ok());
+        let errors = Program::compile_main(sources).unwrap_err();
+        let rendered = errors.to_string();
+        assert!(rendered.starts_with("error: no `main` function defined\n --> b.sk:1:10\n"));
+    }
+
+    #[test]
+    fn reports_diagnostics_with_sources() {
+        let errors = compile("fn main() { let x: int = \"a\"; }\nfn f( {}").unwrap_err();
+        assert_eq!(errors.iter().count(), 1);
+        assert!(errors.to_string().starts_with("error: "));
+        assert!(errors.to_string().contains(" --> main.sk:2:"));
+
+        let errors = compile("fn main() { let x: int = \"a\"; }").unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|d| d.message.as_str())
+                .collect::<Vec<_>>(),
+            vec!["expected `int`, found `str`"]
+        );
+    }
+}
@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::analysis::{Analysis, SymbolKind};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::types::Span;
+
+/// JSON-RPC error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_PARAMS: i64 = -32602;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// An open file.
+struct Document {
+    text: String,
+    /// The analysis of the latest version that parsed. Completion keeps working with it while
+    /// the user is in the middle of typing something.
+    analysis: Option<Analysis>,
+    /// Whether `analysis` is of the current text, which the other requests need.
+    fresh: bool,
+}
+
+/// A language server for `.sk` files, speaking the Language Server Protocol over `input` and
+/// `output`. It returns after the client sends `exit`, or when `input` ends.
+///
+/// Files are sent whole on every change. The server publishes their syntax and type errors,
+/// and answers `textDocument/definition`, `hover`, `completion` and `documentSymbol`.
+pub fn serve(mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut server = Server {
+        output,
+        documents: HashMap::new(),
+    };
+    while let Some(body) = read_message(&mut input)? {
+        let message: Value = match serde_json::from_slice(&body) {
+            Ok(message) => message,
+            Err(err) => {
+                let error = json!({ "code": PARSE_ERROR, "message": err.to_string() });
+                server.send(json!({ "jsonrpc": "2.0", "id": null, "error": error }))?;
+                continue;
+            }
+        };
+        // Messages without a method are responses to requests from the server, which sends none.
+        let Some(method) = message["method"].as_str() else {
+            continue;
+        };
+        let params = &message["params"];
+        match message.get("id") {
+            Some(id) => {
+                let response = match server.request(method, params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": message },
+                    }),
+                };
+                server.send(response)?;
+            }
+            None if method == "exit" => break,
+            None => server.notification(method, params)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the body of the next message, or `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let Some(length) = length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message without a Content-Length",
+        ));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+struct Server<W> {
+    output: W,
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> Server<W> {
+    fn send(&mut self, message: Value) -> io::Result<()> {
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["."] },
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "slang-lsp", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/definition" => {
+                let (uri, document, offset) = self.position(params)?;
+                let symbol = document
+                    .fresh
+                    .then(|| document.analysis.as_ref()?.definition_at(offset))
+                    .flatten();
+                Ok(match symbol {
+                    Some(symbol) => json!({
+                        "uri": uri,
+                        "range": range(&document.text, symbol.name_span),
+                    }),
+                    None => Value::Null,
+                })
+            }
+            "textDocument/hover" => {
+                let (_, document, offset) = self.position(params)?;
+                let hover = document
+                    .fresh
+                    .then(|| document.analysis.as_ref()?.hover_at(offset))
+                    .flatten();
+                Ok(match hover {
+                    Some((markdown, span)) => json!({
+                        "contents": { "kind": "markdown", "value": markdown },
+                        "range": range(&document.text, span),
+                    }),
+                    None => Value::Null,
+                })
+            }
+            "textDocument/completion" => {
+                let (_, document, offset) = self.position(params)?;
+                let Some(analysis) = &document.analysis else {
+                    return Ok(json!([]));
+                };
+                let items: Vec<Value> = analysis
+                    .completions(&document.text, offset)
+                    .into_iter()
+                    .map(|completion| {
+                        json!({
+                            "label": completion.label,
+                            "kind": completion_kind(completion.kind),
+                            "detail": completion.detail,
+                        })
+                    })
+                    .collect();
+                Ok(Value::Array(items))
+            }
+            "textDocument/documentSymbol" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let Some(document) = self.documents.get(uri) else {
+                    return Err((INVALID_PARAMS, format!("`{}` is not open", uri)));
+                };
+                let Some(analysis) = document.analysis.as_ref().filter(|_| document.fresh) else {
+                    return Ok(json!([]));
+                };
+                let symbols: Vec<Value> = analysis
+                    .definitions()
+                    .map(|symbol| {
+                        json!({
+                            "name": symbol.name,
+                            "kind": symbol_kind(symbol.kind),
+                            "detail": symbol.detail,
+                            "range": range(&document.text, symbol.span),
+                            "selectionRange": range(&document.text, symbol.name_span),
+                        })
+                    })
+                    .collect();
+                Ok(Value::Array(symbols))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method `{}`", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> io::Result<()> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // The whole text is sent, so only the last change matters.
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return self.publish(uri, vec![]);
+            }
+            _ => None,
+        };
+        let Some(text) = text else {
+            return Ok(());
+        };
+
+        let (analysis, diagnostics) = match Analysis::new(text) {
+            Ok(analysis) => {
+                let diagnostics = analysis.diagnostics.clone();
+                (Some(analysis), diagnostics)
+            }
+            Err(diagnostics) => (None, diagnostics),
+        };
+        let diagnostics = diagnostics
+            .iter()
+            .map(|diagnostic| lsp_diagnostic(uri, text, diagnostic))
+            .collect();
+        let previous = self.documents.remove(uri).and_then(|d| d.analysis);
+        self.documents.insert(
+            uri.to_string(),
+            Document {
+                text: text.to_string(),
+                fresh: analysis.is_some(),
+                analysis: analysis.or(previous),
+            },
+        );
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Value>) -> io::Result<()> {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }))
+    }
+
+    /// The document and byte offset of a `TextDocumentPositionParams`.
+    fn position<'a>(
+        &'a self,
+        params: &'a Value,
+    ) -> Result<(&'a str, &'a Document, usize), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let Some(document) = self.documents.get(uri) else {
+            return Err((INVALID_PARAMS, format!("`{}` is not open", uri)));
+        };
+        let (Some(line), Some(character)) = (
+            params["position"]["line"].as_u64(),
+            params["position"]["character"].as_u64(),
+        ) else {
+            return Err((INVALID_PARAMS, "expected a position".to_string()));
+        };
+        Ok((uri, document, offset(&document.text, line, character)))
+    }
+}
+
+fn lsp_diagnostic(uri: &str, text: &str, diagnostic: &Diagnostic) -> Value {
+    let mut message = diagnostic.message.clone();
+    for note in &diagnostic.notes {
+        message.push_str(&format!("\nnote: {}", note));
+    }
+    let related: Vec<Value> = diagnostic
+        .secondary
+        .iter()
+        .map(|label| {
+            json!({
+                "location": { "uri": uri, "range": range(text, label.span) },
+                "message": label.message,
+            })
+        })
+        .collect();
+    json!({
+        "range": range(text, diagnostic.primary.span),
+        "severity": match diagnostic.severity {
+            Severity::Error => 1,
+            Severity::Warning => 2,
+        },
+        "source": "slang",
+        "message": message,
+        "relatedInformation": related,
+    })
+}
+
+fn completion_kind(kind: SymbolKind) -> u8 {
+    match kind {
+        SymbolKind::Function => 3,
+        SymbolKind::Field => 5,
+        SymbolKind::Global | SymbolKind::Local => 6,
+        SymbolKind::Struct => 22,
+    }
+}
+
+fn symbol_kind(kind: SymbolKind) -> u8 {
+    match kind {
+        SymbolKind::Field => 8,
+        SymbolKind::Function => 12,
+        SymbolKind::Global | SymbolKind::Local => 13,
+        SymbolKind::Struct => 23,
+    }
+}
+
+fn range(text: &str, span: Span) -> Value {
+    json!({ "start": position(text, span.start), "end": position(text, span.end) })
+}
+
+/// The LSP position of a byte offset. Characters are counted in UTF-16 code units.
+fn position(text: &str, offset: usize) -> Value {
+    let offset = offset.min(text.len());
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": text[..line_start].matches('\n').count(),
+        "character": text[line_start..offset].encode_utf16().count(),
+    })
+}
+
+/// The byte offset of an LSP position, clamped to the end of its line.
+fn offset(text: &str, line: u64, character: u64) -> usize {
+    let mut line_start = 0;
+    for _ in 0..line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16() as u64;
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Send `messages` to a server as a client would, and return everything it sends back.
+    fn session(messages: &[Value]) -> Vec<Value> {
+        let mut input = vec![];
+        for message in messages {
+            let body = message.to_string();
+            write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+        let mut output = vec![];
+        serve(&input[..], &mut output).unwrap();
+
+        let mut output = &output[..];
+        let mut replies = vec![];
+        while let Some(body) = read_message(&mut output).unwrap() {
+            replies.push(serde_json::from_slice(&body).unwrap());
+        }
+        replies
+    }
+
+    fn request(id: u64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    fn notification(method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "method": method, "params": params })
+    }
+
+    fn open(text: &str) -> Value {
+        let document = json!({ "uri": "file:///main.sk", "languageId": "samlang", "version": 1, "text": text });
+        notification("textDocument/didOpen", json!({ "textDocument": document }))
+    }
+
+    fn at(id: u64, method: &str, line: u64, character: u64) -> Value {
+        let params = json!({
+            "textDocument": { "uri": "file:///main.sk" },
+            "position": { "line": line, "character": character },
+        });
+        request(id, method, params)
+    }
+
+    const SOURCE: &str = "struct Pos { x: int, y: int }
+/// Moves `p` to the right.
+fn shift(p: Pos, by: int) -> Pos {
+    let moved: Pos = { x = p.x + by, y = p.y };
+    return moved;
+}
+fn main() -> int { return shift({ x = 1, y = 2 }, 3).x; }
+";
+
+    #[test]
+    fn initializes_and_shuts_down() {
+        let replies = session(&[
+            request(1, "initialize", json!({ "capabilities": {} })),
+            notification("initialized", json!({})),
+            json!({ "jsonrpc": "2.0", "id": 7, "result": null }),
+            json!({ "jsonrpc": "2.0", "id": 8, "error": { "code": -32601, "message": "no" } }),
+            request(2, "textDocument/rename", json!({})),
+            request(3, "shutdown", Value::Null),
+            notification("exit", Value::Null),
+            request(4, "shutdown", Value::Null),
+        ]);
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["id"], 1);
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(replies[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(
+            replies[2],
+            json!({ "jsonrpc": "2.0", "id": 3, "result": null })
+        );
+    }
+
+    #[test]
+    fn publishes_diagnostics_on_change() {
+        let change = |text: &str| {
+            let params = json!({
+                "textDocument": { "uri": "file:///main.sk", "version": 2 },
+                "contentChanges": [{ "text": text }],
+            });
+            notification("textDocument/didChange", params)
+        };
+        let replies = session(&[
+            open("fn main() {\n    let s: str = 1;\n}\n"),
+            change("fn main( {}"),
+            change("fn main() {}"),
+        ]);
+        let diagnostics: Vec<&Value> = replies
+            .iter()
+            .map(|reply| &reply["params"]["diagnostics"])
+            .collect();
+        assert_eq!(diagnostics[0][0]["message"], "expected `str`, found `int`");
+        assert_eq!(
+            diagnostics[0][0]["range"],
+            json!({ "start": { "line": 1, "character": 17 }, "end": { "line": 1, "character": 18 } })
+        );
+        assert_eq!(diagnostics[1].as_array().unwrap().len(), 1);
+        assert_eq!(diagnostics[2], &json!([]));
+    }
+
+    #[test]
+    fn answers_questions_about_names() {
+        let replies = session(&[
+            open(SOURCE),
+            at(1, "textDocument/definition", 4, 12),
+            at(2, "textDocument/definition", 6, 27),
+            at(3, "textDocument/definition", 2, 13),
+            at(4, "textDocument/hover", 6, 28),
+            at(5, "textDocument/hover", 3, 29),
+            request(
+                6,
+                "textDocument/documentSymbol",
+                json!({ "textDocument": { "uri": "file:///main.sk" } }),
+            ),
+        ]);
+        let range = |line, start, end| json!({ "start": { "line": line, "character": start }, "end": { "line": line, "character": end } });
+        // `moved` in `return moved;`, `shift` in `main` and the `Pos` type of `p`.
+        assert_eq!(replies[1]["result"]["range"], range(3, 8, 13));
+        assert_eq!(replies[2]["result"]["range"], range(2, 3, 8));
+        assert_eq!(replies[3]["result"]["range"], range(0, 7, 10));
+
+        assert_eq!(
+            replies[4]["result"]["contents"]["value"],
+            "```samlang\nfn shift(p: Pos, by: int) -> Pos\n```\n\nMoves `p` to the right."
+        );
+        assert_eq!(
+            replies[5]["result"]["contents"]["value"],
+            "```samlang\nx: int\n```"
+        );
+
+        let symbols: Vec<(&Value, &Value)> = replies[6]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|symbol| (&symbol["name"], &symbol["kind"]))
+            .collect();
+        assert_eq!(
+            symbols,
+            vec![
+                (&json!("Pos"), &json!(23)),
+                (&json!("shift"), &json!(12)),
+                (&json!("main"), &json!(12))
+            ]
+        );
+    }
+
+    #[test]
+    fn completes_names_and_fields() {
+        // The second version does not parse, so the completions come from the first one.
+        let typing = SOURCE.replace("return moved;", "return moved.");
+        let params = json!({
+            "textDocument": { "uri": "file:///main.sk", "version": 2 },
+            "contentChanges": [{ "text": typing }],
+        });
+        let replies = session(&[
+            open(SOURCE),
+            at(1, "textDocument/completion", 4, 11),
+            notification("textDocument/didChange", params),
+            at(2, "textDocument/completion", 4, 17),
+        ]);
+        let labels = |reply: &Value| -> Vec<String> {
+            reply["result"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["label"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(
+            labels(&replies[1]),
+            vec!["Pos", "by", "format", "main", "moved", "p", "print", "shift"]
+        );
+        assert_eq!(labels(&replies[3]), vec!["x", "y"]);
+        assert_eq!(replies[3]["result"][0]["detail"], "x: int");
+    }
+
+    #[test]
+    fn positions_count_utf16_units() {
+        let text = "let é = \"😀\";\nx";
+        assert_eq!(position(text, 14), json!({ "line": 0, "character": 11 }));
+        assert_eq!(offset(text, 0, 11), 14);
+        assert_eq!(offset(text, 0, 99), 16);
+        assert_eq!(offset(text, 1, 0), 17);
+        assert_eq!(offset(text, 5, 0), text.len());
+    }
+}
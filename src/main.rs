@@ -1,36 +1,446 @@
+use std::io::Read;
+use std::path::Path;
 use std::process;
+use std::thread;
 
-use parser::Parser;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use slang::repl::Repl;
+use slang::tokenizer::Tokenizer;
+use slang::{bundle, formatter, Diagnostic, Diagnostics, Limits, Program, SourceMap, Value};
 
-pub mod parser;
-pub mod tokenizer;
-pub mod types;
+const USAGE: &str = "\
+Usage: slang [command] [options] <file>...
+       slang repl
+
+Commands:
+    run                 Type check and run the program (the default)
+    check               Only type check the program
+    tokens              Print the tokens of each file
+    ast                 Print the syntax tree of each file
+    build               Write a standalone executable that runs the program. It carries the
+                        sources, which are checked again each time it starts
+    fmt                 Print each file in the canonical style
+    repl                Evaluate definitions, statements and expressions interactively
+
+Options:
+    -o <file>           Where `build` writes the executable, by default the name of the
+                        first file without its extension
+    --emit=<stage>,...  Also print the `tokens` or `ast` of each file while compiling
+    --check             With `fmt`, fail if a file is not formatted instead of printing it
+    --write             With `fmt`, rewrite the files in place
+    --fuel=<steps>      With `run` and `repl`, stop after this many expressions and
+                        statements (default 100000000)
+    --max-depth=<calls> With `run` and `repl`, how deeply calls may nest (default 10000)
+    --max-alloc=<bytes> With `run` and `repl`, how many bytes may be allocated over the run in
+                        total, counting every copy (default 1073741824)
+    -h, --help          Print this message
+
+A limit of `none` turns it off. Executables written by `build` run within the defaults.
+
+The files of a program are compiled together. A file named `-` is read from standard input.
+
+Exit status:
+    0   Success. `run` exits with the value returned by `main` instead.
+    1   The program has syntax, type or runtime errors, or `fmt --check` found a file that
+        is not formatted.
+    2   The command line is invalid, or a file could not be read or written.
+";
+
+/// The stack the command runs on. The parser recurses on it, so deeply nested source needs
+/// more than a main thread has. It is only reserved, so the memory is not used unless needed.
+const STACK_SIZE: usize = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    Run,
+    Check,
+    Tokens,
+    Ast,
+    Build,
+    Fmt,
+    Repl,
+}
+
+/// An intermediate stage printed by `--emit`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    Tokens,
+    Ast,
+}
+
+#[derive(Debug)]
+struct Options {
+    command: Command,
+    files: Vec<String>,
+    output: Option<String>,
+    emit: Vec<Emit>,
+    check: bool,
+    write: bool,
+    limits: Limits,
+    /// Whether any of `limits` was given.
+    limited: bool,
+}
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <filename>", args[0]);
-        std::process::exit(1);
+    // Everything runs on a thread with a large stack.
+    match thread::Builder::new().stack_size(STACK_SIZE).spawn(cli) {
+        Ok(cli) => {
+            // A panic has already printed its message.
+            if cli.join().is_err() {
+                process::exit(101);
+            }
+        }
+        Err(err) => fail(&format!("cannot start a thread: {}", err)),
     }
+}
 
-    let filename = &args[1];
-    let source = std::fs::read_to_string(&filename).expect("Failed to read file");
+fn cli() {
+    // An executable written by `slang build` runs the program it carries.
+    if let Some(sources) = bundle::embedded_sources() {
+        run(compile(sources, &[]), Limits::default());
+    }
 
-    let mut tokenizer = tokenizer::Tokenizer::new(filename.to_string());
-    let tokens = match tokenizer.tokenize(&source) {
-        Ok(tokens) => tokens,
-        Err(msg) => {
-            println!("{}", msg);
-            process::exit(1);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", USAGE);
+        process::exit(0);
+    }
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!(
+                "error: {}\n\nFor more information, try `slang --help`.",
+                message
+            );
+            process::exit(2);
+        }
+    };
+
+    let mut sources = SourceMap::new();
+    for filename in &options.files {
+        let text = if filename == "-" {
+            let mut text = String::new();
+            std::io::stdin()
+                .read_to_string(&mut text)
+                .map(|_| text)
+                .map_err(|err| err.to_string())
+        } else {
+            std::fs::read_to_string(filename).map_err(|err| err.to_string())
+        };
+        match text {
+            Ok(text) => sources.add(filename.to_string(), text),
+            Err(err) => fail(&format!("cannot read `{}`: {}", filename, err)),
+        };
+    }
+
+    match options.command {
+        Command::Tokens => {
+            for file_id in 0..sources.files().len() {
+                let mut tokenizer = Tokenizer::new(file_id);
+                match tokenizer.tokenize(&sources.get(file_id).text) {
+                    Ok(tokens) => tokenizer.print_tokens(&tokens),
+                    Err(diagnostics) => report_in(&sources, diagnostics),
+                }
+            }
+        }
+        Command::Ast => match slang::parse(&sources) {
+            Ok(ast) => println!("{:#?}", ast),
+            Err(diagnostics) => report_in(&sources, diagnostics),
+        },
+        Command::Check => {
+            compile(sources, &options.emit);
+        }
+        Command::Run => run(compile(sources, &options.emit), options.limits),
+        Command::Build => {
+            let program = compile(sources, &options.emit);
+            let output = options.output.unwrap_or_else(|| {
+                let first = Path::new(&options.files[0]).file_stem();
+                match first.and_then(|stem| stem.to_str()) {
+                    Some(stem) if stem != "-" => stem.to_string(),
+                    _ => "a.out".to_string(),
+                }
+            });
+            if let Err(err) = bundle::write_executable(program.sources(), Path::new(&output)) {
+                fail(&format!("cannot write `{}`: {}", output, err));
+            }
         }
+        Command::Fmt => fmt(&options, &sources),
+        Command::Repl => repl(options.limits),
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let command = match args.first().map(String::as_str) {
+        Some("run") => Some(Command::Run),
+        Some("check") => Some(Command::Check),
+        Some("tokens") => Some(Command::Tokens),
+        Some("ast") => Some(Command::Ast),
+        Some("build") => Some(Command::Build),
+        Some("fmt") => Some(Command::Fmt),
+        Some("repl") => Some(Command::Repl),
+        _ => None,
     };
+    let mut args = args.into_iter().skip(usize::from(command.is_some()));
 
-    //tokenizer.print_tokens(&tokens);
+    let mut options = Options {
+        // `slang file.sk` runs the file.
+        command: command.unwrap_or(Command::Run),
+        files: vec![],
+        output: None,
+        emit: vec![],
+        check: false,
+        write: false,
+        limits: Limits::default(),
+        limited: false,
+    };
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            options.output = Some(args.next().ok_or("`-o` needs a file name")?);
+        } else if let Some(stages) = arg.strip_prefix("--emit=") {
+            for stage in stages.split(',') {
+                options.emit.push(match stage {
+                    "tokens" => Emit::Tokens,
+                    "ast" => Emit::Ast,
+                    _ => return Err(format!("unknown stage `{}` for `--emit`", stage)),
+                });
+            }
+        } else if arg == "--check" {
+            options.check = true;
+        } else if arg == "--write" {
+            options.write = true;
+        } else if let Some(fuel) = arg.strip_prefix("--fuel=") {
+            options.limits.fuel = parse_limit("--fuel", fuel)?;
+            options.limited = true;
+        } else if let Some(depth) = arg.strip_prefix("--max-depth=") {
+            options.limits.call_depth = parse_limit("--max-depth", depth)?;
+            options.limited = true;
+        } else if let Some(bytes) = arg.strip_prefix("--max-alloc=") {
+            options.limits.allocations = parse_limit("--max-alloc", bytes)?;
+            options.limited = true;
+        } else if arg.starts_with('-') && arg != "-" {
+            return Err(format!("unknown option `{}`", arg));
+        } else {
+            options.files.push(arg);
+        }
+    }
+
+    if options.command == Command::Repl {
+        if !options.files.is_empty() {
+            return Err("`repl` does not take files".to_string());
+        }
+    } else if options.files.is_empty() {
+        return Err("no input files".to_string());
+    }
+    if options.output.is_some() && options.command != Command::Build {
+        return Err("`-o` can only be used with `build`".to_string());
+    }
+    if (options.check || options.write) && options.command != Command::Fmt {
+        return Err("`--check` and `--write` can only be used with `fmt`".to_string());
+    }
+    if options.check && options.write {
+        return Err("`--check` and `--write` cannot be used together".to_string());
+    }
+    if options.limited && !matches!(options.command, Command::Run | Command::Repl) {
+        return Err("limits can only be used with `run` and `repl`".to_string());
+    }
+    Ok(options)
+}
+
+/// The value of a limit option: a number, or `none` for no limit.
+fn parse_limit<T: std::str::FromStr>(option: &str, value: &str) -> Result<Option<T>, String> {
+    if value == "none" {
+        return Ok(None);
+    }
+    match value.parse() {
+        Ok(limit) => Ok(Some(limit)),
+        Err(_) => Err(format!("invalid limit `{}` for `{}`", value, option)),
+    }
+}
 
-    let mut parser = Parser::new(filename.to_string(), tokens);
-    if let Err(err) = parser.parse() {
-        println!("{}", err);
+/// Compile the program, printing the stages in `emit` on the way. Exits with the diagnostics
+/// if it has errors.
+fn compile(sources: SourceMap, emit: &[Emit]) -> Program {
+    if emit.contains(&Emit::Tokens) {
+        for (file_id, file) in sources.files().iter().enumerate() {
+            let mut tokenizer = Tokenizer::new(file_id);
+            // Errors are reported when the program is compiled below.
+            if let Ok(tokens) = tokenizer.tokenize(&file.text) {
+                tokenizer.print_tokens(&tokens);
+            }
+        }
+    }
+    if emit.contains(&Emit::Ast) {
+        if let Ok(ast) = slang::parse(&sources) {
+            println!("{:#?}", ast);
+        }
+    }
+
+    match Program::compile_main(sources) {
+        Ok(program) => program,
+        Err(diagnostics) => report(&diagnostics),
+    }
+}
+
+/// Run the program within `limits` and exit with the value `main` returns.
+fn run(program: Program, limits: Limits) -> ! {
+    let program = program.with_limits(limits);
+    match program.run() {
+        Ok(Value::Int(n)) => process::exit(n as i32),
+        Ok(Value::Void) => process::exit(0),
+        Ok(value) => {
+            eprintln!("error: `main` must return an int, got {}", value);
+            process::exit(1);
+        }
+        Err(err) => match err.diagnostic() {
+            Some(diagnostic) => report_in(program.sources(), vec![diagnostic]),
+            None => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        },
+    }
+}
+
+/// `slang fmt` prints each file in the canonical style. `--check` only reports the files that
+/// are not formatted and fails if there are any, and `--write` rewrites them in place.
+fn fmt(options: &Options, sources: &SourceMap) {
+    let mut unformatted = false;
+    for (file_id, file) in sources.files().iter().enumerate() {
+        let formatted = match formatter::format_file(file_id, &file.text) {
+            Ok(formatted) => formatted,
+            Err(diagnostics) => report_in(sources, diagnostics),
+        };
+        if options.check {
+            if formatted != file.text {
+                eprintln!("{} is not formatted", file.name);
+                unformatted = true;
+            }
+        } else if options.write && file.name != "-" {
+            if formatted != file.text {
+                if let Err(err) = std::fs::write(&file.name, formatted) {
+                    fail(&format!("cannot write `{}`: {}", file.name, err));
+                }
+            }
+        } else {
+            print!("{}", formatted);
+        }
+    }
+    if unformatted {
         process::exit(1);
     }
-    process::exit(0);
+}
+
+/// `slang repl` evaluates one input at a time until the end of input. An input continues on
+/// the next line while it has open brackets. The history is kept in `~/.slang_history`.
+fn repl(limits: Limits) {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(err) => fail(&format!("cannot read input: {}", err)),
+    };
+    let history = std::env::var_os("HOME").map(|home| Path::new(&home).join(".slang_history"));
+    if let Some(history) = &history {
+        // There is no history before the first session.
+        let _ = editor.load_history(history);
+    }
+
+    let mut session = Repl::new().with_limits(limits);
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "> " } else { ". " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                input.push_str(&line);
+                input.push('\n');
+                if !Repl::is_complete(&input) {
+                    continue;
+                }
+                let _ = editor.add_history_entry(input.trim_end());
+                match session.eval(&input) {
+                    Ok(output) if output.is_empty() => {}
+                    Ok(output) => println!("{}", output),
+                    Err(err) => eprintln!("{}", err),
+                }
+                input.clear();
+            }
+            // Ctrl-C discards the input being typed.
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(err) => fail(&format!("cannot read input: {}", err)),
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(err) = editor.save_history(history) {
+            eprintln!("warning: cannot save the history: {}", err);
+        }
+    }
+}
+
+/// Print every diagnostic with the source it points at and exit with a failure.
+fn report(diagnostics: &Diagnostics) -> ! {
+    eprintln!("{}", diagnostics);
+    process::exit(1);
+}
+
+/// Report errors found in the files of `sources` outside of `Program::compile`.
+fn report_in(sources: &SourceMap, diagnostics: Vec<Diagnostic>) -> ! {
+    report(&Diagnostics::new(sources.clone(), diagnostics))
+}
+
+/// Print an error that is not about the program, such as a missing file, and exit.
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(2);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse_args(line.split_whitespace().map(str::to_string).collect())
+    }
+
+    #[test]
+    fn parses_commands_and_options() {
+        let options = args("a.sk").unwrap();
+        assert_eq!(
+            (options.command, options.files),
+            (Command::Run, vec!["a.sk".to_string()])
+        );
+
+        let options = args("build -o out --emit=tokens,ast a.sk - b.sk").unwrap();
+        assert_eq!(options.command, Command::Build);
+        assert_eq!(options.output.as_deref(), Some("out"));
+        assert_eq!(options.emit, vec![Emit::Tokens, Emit::Ast]);
+        assert_eq!(options.files, vec!["a.sk", "-", "b.sk"]);
+
+        assert!(args("fmt --check a.sk").unwrap().check);
+        assert!(args("run").is_err());
+        assert_eq!(args("repl").unwrap().command, Command::Repl);
+        assert!(args("repl a.sk").is_err());
+        assert!(args("run -o out a.sk").is_err());
+        assert!(args("check --emit=bytes a.sk").is_err());
+        assert!(args("fmt --check --write a.sk").is_err());
+        assert!(args("run --verbose a.sk").is_err());
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(args("a.sk").unwrap().limits, Limits::default());
+        let options = args("run --fuel=1000 --max-depth=none --max-alloc=4096 a.sk").unwrap();
+        assert_eq!(
+            options.limits,
+            Limits {
+                fuel: Some(1000),
+                call_depth: None,
+                allocations: Some(4096),
+            }
+        );
+        assert_eq!(args("repl --fuel=none").unwrap().limits.fuel, None);
+        assert!(args("run --fuel=-1 a.sk").is_err());
+        assert!(args("run --max-depth= a.sk").is_err());
+        assert!(args("check --fuel=10 a.sk").is_err());
+    }
 }
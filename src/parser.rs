@@ -1,56 +1,203 @@
-use crate::types::{Definition, Keyword, Primitive, Statement, Token, TokenType, Type, AST};
+use crate::captures;
+use crate::diagnostic::{Diagnostic, Label};
+use crate::types::{
+    AnonFn, Definition, Expression, ExpressionKind, Function, Item, Keyword, Literal, Op, Param,
+    Primitive, Span, Spanned, Statement, StatementKind, Struct, Token, TokenType, Type, AST,
+};
+
+/// The name, type annotation and value of `let name[: type] = expr;`.
+type Binding = (Spanned<String>, Option<Spanned<Type>>, Expression);
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Parser {
-    file: String,
     tokens: Vec<Token>,
     token_index: usize,
+    /// Set while parsing `if`/`while`/`for` headers, where `name {` opens the body rather than
+    /// a struct literal.
+    no_struct_literal: bool,
+    /// Errors recovered from so far.
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
-    pub fn new(file: String, tokens: Vec<Token>) -> Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
         Parser {
-            file,
             tokens,
             token_index: 0,
+            no_struct_literal: false,
+            diagnostics: vec![],
         }
     }
 
-    /// Build the syntax tree and report potential errors.
-    pub fn parse(&mut self) -> Result<AST, String> {
+    /// Build the syntax tree and report potential errors. Parsing continues after an error so
+    /// that every error in the file is reported at once.
+    pub fn parse(&mut self) -> Result<AST, Vec<Diagnostic>> {
         let mut ast = AST::new();
-        loop {
-            self.next_non_whitespace_token();
+        self.skip_whitespace();
+        while !self.end_of_tokens() {
             match self.parse_definition() {
-                Ok(def) => ast.definitions.push(def),
-                Err(err) => {
-                    if self.end_of_tokens() {
-                        break;
-                    }
-                    return Err(err);
+                Ok(definition) => ast.definitions.push(definition),
+                Err(diagnostic) => {
+                    self.diagnostics.push(*diagnostic);
+                    self.synchronize_definition();
+                    continue;
                 }
             }
-
+            self.next_non_whitespace_token();
+        }
+        if !self.diagnostics.is_empty() {
+            return Err(std::mem::take(&mut self.diagnostics));
         }
+        captures::analyze(&mut ast);
 
         Ok(ast)
     }
 
+    /// Parse the tokens as a single type, such as the signature of a host function.
+    pub fn parse_type_only(&mut self) -> Result<Type, Vec<Diagnostic>> {
+        self.skip_whitespace();
+        let t = self.parse_type().map_err(|diagnostic| vec![*diagnostic])?;
+        self.next_non_whitespace_token();
+        if let Ok(token) = self.current_token() {
+            let msg = format!("expected the end of the type, got {:?}", token.token_type);
+            return Err(vec![*self.error(msg)]);
+        }
+
+        Ok(t)
+    }
+
+    /// Parse the tokens as a single expression, such as an input to the REPL.
+    pub fn parse_expression_only(&mut self) -> Result<Expression, Vec<Diagnostic>> {
+        self.skip_whitespace();
+        let expr = self
+            .parse_expression()
+            .map_err(|diagnostic| vec![*diagnostic])?;
+        self.next_non_whitespace_token();
+        if let Ok(token) = self.current_token() {
+            let msg = format!(
+                "expected the end of the expression, got {:?}",
+                token.token_type
+            );
+            return Err(vec![*self.error(msg)]);
+        }
+
+        Ok(expr)
+    }
+
+    /// Parse the tokens as top level definitions and statements in any order, such as an input
+    /// to the REPL. `fn`, `struct` and `let` start definitions.
+    pub fn parse_items(&mut self) -> Result<Vec<Item>, Vec<Diagnostic>> {
+        let mut items = vec![];
+        self.skip_whitespace();
+        while let Ok(token) = self.current_token() {
+            let item = match token.token_type {
+                TokenType::Keyword(Keyword::Fn | Keyword::Struct | Keyword::Let) => {
+                    self.parse_definition().map(Item::Definition)
+                }
+                _ => self.parse_statement().map(Item::Statement),
+            };
+            match item {
+                Ok(item) => items.push(item),
+                Err(diagnostic) => {
+                    self.diagnostics.push(*diagnostic);
+                    break;
+                }
+            }
+            self.next_non_whitespace_token();
+        }
+        if !self.diagnostics.is_empty() {
+            return Err(std::mem::take(&mut self.diagnostics));
+        }
+
+        Ok(items)
+    }
+
+    /// Skip to the start of the next top level definition after an error, jumping over any
+    /// braced bodies on the way.
+    fn synchronize_definition(&mut self) {
+        let mut depth = 0;
+        // Always make progress, the current token is the one that failed.
+        self.next_non_whitespace_token();
+        while let Ok(token) = self.current_token() {
+            match token.token_type {
+                TokenType::LeftCurly => depth += 1,
+                TokenType::RightCurly if depth > 0 => depth -= 1,
+                TokenType::Keyword(Keyword::Fn | Keyword::Let | Keyword::Struct) if depth == 0 => {
+                    return
+                }
+                _ => {}
+            }
+            self.next_non_whitespace_token();
+        }
+    }
+
+    /// Skip to the end of the current statement after an error: past the next `;`, or up to
+    /// the `}` closing the enclosing block.
+    fn synchronize_statement(&mut self) {
+        let mut depth = 0;
+        while let Ok(token) = self.current_token() {
+            match token.token_type {
+                TokenType::LeftCurly => depth += 1,
+                TokenType::RightCurly if depth == 0 => {
+                    self.token_index -= 1;
+                    return;
+                }
+                TokenType::RightCurly => {
+                    depth -= 1;
+                    // A nested block closing ends a block statement such as `if`.
+                    if depth == 0 && !self.peek_is(TokenType::Semicolon) {
+                        return;
+                    }
+                }
+                TokenType::Semicolon if depth == 0 => return,
+                _ => {}
+            }
+            self.next_non_whitespace_token();
+        }
+    }
+
     /// Parse a definition. It can be a function definition, global variable or a struct.
     /// Any unexpected tokens result in an error.
-    fn parse_definition(&mut self) -> Result<Definition, String> {
-        if self.match_token(TokenType::Keyword(Keyword::Fn)).is_ok() {
-            self.parse_fn_def()
+    fn parse_definition(&mut self) -> Result<Definition, Box<Diagnostic>> {
+        let doc = self.doc_comment();
+        let mut definition = if self.match_token(TokenType::Keyword(Keyword::Fn)).is_ok() {
+            self.parse_fn_def()?
         } else if self.match_token(TokenType::Keyword(Keyword::Let)).is_ok() {
-            self.parse_global()
+            self.parse_global()?
         } else if self
             .match_token(TokenType::Keyword(Keyword::Struct))
             .is_ok()
         {
-            self.parse_struct_def()
+            self.parse_struct_def()?
         } else {
             return Err(self.error("expected a top level definition".to_string()));
+        };
+        match &mut definition {
+            Definition::FnDef(function) => function.doc = doc,
+            Definition::GlobalDef(.., d) => *d = doc,
+            Definition::StructDef(s) => s.doc = doc,
+        }
+
+        Ok(definition)
+    }
+
+    /// Join the `///` comments among the whitespace and comments right before the current
+    /// token.
+    fn doc_comment(&self) -> Option<String> {
+        let mut lines: Vec<&str> = self.tokens[..self.token_index]
+            .iter()
+            .rev()
+            .take_while(|token| Self::is_whitespace(token))
+            .filter_map(|token| match &token.token_type {
+                TokenType::DocComment(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        if lines.is_empty() {
+            return None;
         }
+        lines.reverse();
+        Some(lines.join("\n"))
     }
 
     /// Parse a function.
@@ -60,13 +207,15 @@ impl Parser {
     ///     // [statement]
     /// }
     /// ```
-    fn parse_fn_def(&mut self) -> Result<Definition, String> {
+    fn parse_fn_def(&mut self) -> Result<Definition, Box<Diagnostic>> {
         self.match_token(TokenType::Keyword(Keyword::Fn))?; // fail here
+        let start = self.span();
 
         self.next_non_whitespace_token();
         self.match_token(TokenType::Identifier("".to_string()))?; // fail here
                                                                   //
         let token = self.current_token()?;
+        let name_span = token.span;
         let function_name = match token.token_type.clone() {
             TokenType::Identifier(name) => name,
             _ => {
@@ -79,7 +228,7 @@ impl Parser {
 
         self.next_non_whitespace_token();
         self.match_token(TokenType::LeftParen)?;
-        let mut params: Vec<(String, Type)> = vec![];
+        let mut params: Vec<Param> = vec![];
         loop {
             self.next_non_whitespace_token();
             if self.match_token(TokenType::RightParen).is_ok() {
@@ -87,129 +236,667 @@ impl Parser {
                 break;
             }
 
-            let token = self.current_token()?;
-            let param_name = match token.token_type {
-                TokenType::Identifier(name) => name,
-                _ => {
-                    return Err(self.error(format!(
-                        "expected parameter identifier but got {:?}",
-                        token.token_type
-                    )))
-                }
-            };
+            params.push(self.parse_param()?);
 
             self.next_non_whitespace_token();
-            self.match_token(TokenType::Colon)?;
 
+            if self.match_token(TokenType::Comma).is_err() {
+                break;
+            }
+        }
+
+        self.match_token(TokenType::RightParen)?;
+        self.next_non_whitespace_token();
+
+        let mut return_type: Option<Spanned<Type>> = None;
+        if self.match_token(TokenType::RetArrow).is_ok() {
             self.next_non_whitespace_token();
-            let token = self.current_token()?;
-            let param_type = match token.token_type {
-                TokenType::Keyword(t) => match t {
-                    Keyword::Type(t) => match t {
-                        Primitive::Int => Type::Int(None),
-                        Primitive::Str => Type::String(None),
-                        Primitive::Bool => Type::Bool(None),
-                        Primitive::Double => Type::Double(None),
-                        _ => return Err(self.error("expected a non-void type".to_string())),
-                    },
-                    // FIXME: tokenizer won't know if this is a struct, match on identifier instead
-                    Keyword::Struct => {
-                        self.next_non_whitespace_token();
+            let t = self.parse_annotation()?;
 
-                        self.match_token(TokenType::Identifier(String::default()))?;
-                        let token = self.current_token()?;
-                        let name = match token.clone().token_type {
-                            TokenType::Identifier(s) => s,
-                            _ => return Err(self.error("expected a struct identifier".to_string())),
-                        };
+            self.next_non_whitespace_token();
+            return_type = Some(t);
+        }
+        let block = self.parse_block()?;
 
-                        Type::Struct(name)
-                    }
-                    t => return Err(self.error(format!("expected a type, got {:?}", t))),
-                },
-                t => return Err(self.error(format!("expected a type, got {:?}", t))),
-            };
+        Ok(Definition::FnDef(Function {
+            name: function_name,
+            params,
+            return_type,
+            body: block,
+            span: self.span_from(start),
+            name_span,
+            doc: None,
+        }))
+    }
 
-            params.push((param_name, param_type));
+    /// Parse a global variable.
+    /// # Example:
+    /// ```sk
+    /// let origin: Position = { x = 0, y = 0 };
+    /// ```
+    fn parse_global(&mut self) -> Result<Definition, Box<Diagnostic>> {
+        let start = self.span();
+        let (name, annotation, value) = self.parse_binding()?;
+        Ok(Definition::GlobalDef(
+            name,
+            annotation,
+            value,
+            self.span_from(start),
+            None,
+        ))
+    }
 
+    /// Parse a struct definition.
+    /// # Example:
+    /// ```sk
+    /// struct Position {
+    ///     x: int,
+    ///     y: int,
+    /// }
+    /// ```
+    fn parse_struct_def(&mut self) -> Result<Definition, Box<Diagnostic>> {
+        self.match_token(TokenType::Keyword(Keyword::Struct))?;
+        let start = self.span();
+        self.next_non_whitespace_token();
+        let name_span = self.current_token()?.span;
+        let name = self.parse_identifier()?;
+
+        self.next_non_whitespace_token();
+        self.match_token(TokenType::LeftCurly)?;
+        let mut fields: Vec<Param> = vec![];
+        loop {
             self.next_non_whitespace_token();
+            if self.match_token(TokenType::RightCurly).is_ok() {
+                break;
+            }
+
+            let field = self.parse_param()?;
+            if fields.iter().any(|(f, _)| *f == field.0) {
+                let message = format!("duplicate field `{}` in `{}`", field.0.node, name);
+                return Err(Box::new(Diagnostic::error(field.0.span, message)));
+            }
+            fields.push(field);
 
+            self.next_non_whitespace_token();
             if self.match_token(TokenType::Comma).is_err() {
+                self.match_token(TokenType::RightCurly)?;
                 break;
             }
         }
 
-        self.match_token(TokenType::RightParen)?;
+        Ok(Definition::StructDef(Struct {
+            name,
+            fields,
+            span: self.span_from(start),
+            name_span,
+            doc: None,
+        }))
+    }
+
+    /// Parse `name: type` of a parameter or struct field. The type cannot be `void`.
+    fn parse_param(&mut self) -> Result<Param, Box<Diagnostic>> {
+        let name = self.parse_name()?;
+        self.next_non_whitespace_token();
+        self.match_token(TokenType::Colon)?;
         self.next_non_whitespace_token();
+        let t = self.parse_annotation()?;
+        if t.node == Type::Void {
+            return Err(self.error("expected a non-void type".to_string()));
+        }
+        Ok((name, t))
+    }
 
-        let mut return_type: Option<Type> = None;
-        if self.match_token(TokenType::RetArrow).is_ok() {
+    /// Parse a type annotation together with its span.
+    fn parse_annotation(&mut self) -> Result<Spanned<Type>, Box<Diagnostic>> {
+        let start = self.span();
+        let t = self.parse_type()?;
+        Ok(Spanned::new(t, self.span_from(start)))
+    }
+
+    /// Parse a type annotation. The current token is the first token of the type.
+    ///
+    /// Function types are written `|param, ...|: return type`, e.g. `|str, int|: bool`. A
+    /// trailing `?` makes a type optional; in a function type it applies to the return type.
+    fn parse_type(&mut self) -> Result<Type, Box<Diagnostic>> {
+        let token = self.current_token()?;
+        let t = match token.token_type {
+            TokenType::Pipe | TokenType::Operator(Op::Or) => {
+                let params = self.parse_pipe_list(|parser| {
+                    let param = parser.parse_type()?;
+                    if param == Type::Void {
+                        return Err(parser.error("expected a non-void type".to_string()));
+                    }
+                    Ok(param)
+                })?;
+
+                self.next_non_whitespace_token();
+                self.match_token(TokenType::Colon)?;
+                self.next_non_whitespace_token();
+                let return_type = self.parse_type()?;
+                Type::Function(params, Box::new(return_type))
+            }
+            TokenType::Keyword(Keyword::Type(t)) => match t {
+                Primitive::Int => Type::Int,
+                Primitive::Str => Type::String,
+                Primitive::Bool => Type::Bool,
+                Primitive::Double => Type::Double,
+                Primitive::Void => Type::Void,
+            },
+            TokenType::Identifier(name) => Type::Struct(name),
+            // Parentheses group a function type to make it optional, as in `(|int|: int)?`.
+            TokenType::LeftParen => {
+                self.next_non_whitespace_token();
+                let t = self.parse_type()?;
+                self.next_non_whitespace_token();
+                self.match_token(TokenType::RightParen)?;
+                t
+            }
+            t => return Err(self.error(format!("expected a type, got {:?}", t))),
+        };
+
+        if let Some(TokenType::Question) = self.peek_non_whitespace_token().map(|t| t.token_type) {
+            self.next_non_whitespace_token();
+            if t == Type::Void {
+                return Err(self.error("`void` cannot be optional".to_string()));
+            }
+            return Ok(Type::Optional(Box::new(t)));
+        }
+
+        Ok(t)
+    }
+
+    /// Parse the items of a `|a, b|` list, such as function type or closure parameters. The
+    /// current token is the opening `|`, or `||` for an empty list, and is left on the closing
+    /// one.
+    fn parse_pipe_list<T>(
+        &mut self,
+        mut parse_item: impl FnMut(&mut Self) -> Result<T, Box<Diagnostic>>,
+    ) -> Result<Vec<T>, Box<Diagnostic>> {
+        let mut items = vec![];
+        // `||` is a single token with nothing in between.
+        if self.match_token(TokenType::Operator(Op::Or)).is_ok() {
+            return Ok(items);
+        }
+        self.match_token(TokenType::Pipe)?;
+        loop {
+            self.next_non_whitespace_token();
+            if self.match_token(TokenType::Pipe).is_ok() {
+                break;
+            }
+
+            items.push(parse_item(self)?);
+
+            self.next_non_whitespace_token();
+            if self.match_token(TokenType::Comma).is_err() {
+                if self.match_token(TokenType::Pipe).is_err() {
+                    return Err(self.error("expected `|`".to_string()));
+                }
+                break;
+            }
+        }
+
+        Ok(items)
+    }
+
+    /// Parse a block of statements.
+    /// # Example:
+    /// ```sk
+    /// {
+    ///     let x = 1;
+    ///     return x;
+    /// }
+    /// ```
+    fn parse_block(&mut self) -> Result<Statement, Box<Diagnostic>> {
+        self.match_token(TokenType::LeftCurly)?;
+        let open = self.span();
+        let mut statements = vec![];
+        loop {
             self.next_non_whitespace_token();
-            self.match_token(TokenType::Keyword(Keyword::Any))?;
-            let t = match self.current_token()?.token_type {
-                TokenType::Keyword(t) => {
-                    if self.keyword_is_type(t.clone()) {
-                        match t {
-                            Keyword::Type(t) => match t {
-                                Primitive::Int => Type::Int(None),
-                                Primitive::Str => Type::String(None),
-                                Primitive::Bool => Type::Bool(None),
-                                Primitive::Double => Type::Double(None),
-                                _ => return Err("expected a non-void type".to_string()),
-                            },
-                            Keyword::Struct => {
-                                self.next_non_whitespace_token();
-
-                                self.match_token(TokenType::Identifier(String::default()))?;
-                                let token = self.current_token()?;
-                                let name = match token.clone().token_type {
-                                    TokenType::Identifier(s) => s,
-                                    _ => return Err("expected a struct identifier".to_string()),
-                                };
-
-                                Type::Struct(name)
-                            }
-                            _ => return Err(self.error("expected a type".to_string())),
-                        }
-                    } else {
-                        return Err(self.error("expected a type".to_string()));
+            if self.end_of_tokens() {
+                let mut diagnostic =
+                    self.error("expected `}`, but reached end of file".to_string());
+                diagnostic.secondary.push(Label {
+                    span: open,
+                    message: "unclosed delimiter".to_string(),
+                });
+                return Err(diagnostic);
+            }
+            if self.match_token(TokenType::RightCurly).is_ok() {
+                break;
+            }
+            match self.parse_statement() {
+                Ok(statement) => statements.push(statement),
+                // Running out of input inside a nested block is reported once, by the caller.
+                Err(diagnostic) if self.end_of_tokens() => return Err(diagnostic),
+                Err(diagnostic) => {
+                    self.diagnostics.push(*diagnostic);
+                    self.synchronize_statement();
+                }
+            }
+        }
+
+        Ok(Statement::new(
+            StatementKind::Block(Box::new(statements)),
+            self.span_from(open),
+        ))
+    }
+
+    /// Parse a single statement. The current token is left on the last token of the statement.
+    fn parse_statement(&mut self) -> Result<Statement, Box<Diagnostic>> {
+        let token = self.current_token()?;
+        let start = token.span;
+        let kind = match token.token_type {
+            TokenType::Keyword(Keyword::Let) => {
+                let (name, annotation, value) = self.parse_binding()?;
+                StatementKind::DeclStmt(name, annotation, value)
+            }
+            TokenType::Keyword(Keyword::Return) => {
+                self.next_non_whitespace_token();
+                if self.match_token(TokenType::Semicolon).is_ok() {
+                    StatementKind::RetStmt(None)
+                } else {
+                    let expr = self.parse_expression()?;
+                    self.next_non_whitespace_token();
+                    self.match_token(TokenType::Semicolon)?;
+                    StatementKind::RetStmt(Some(expr))
+                }
+            }
+            TokenType::Keyword(Keyword::If) => return self.parse_if(),
+            TokenType::Keyword(Keyword::While) => {
+                self.next_non_whitespace_token();
+                let cond = self.parse_condition()?;
+                self.next_non_whitespace_token();
+                let body = self.parse_block()?;
+                StatementKind::WhileStmt(cond, Box::new(body))
+            }
+            TokenType::Keyword(Keyword::For) => {
+                self.next_non_whitespace_token();
+                let name = self.parse_name()?;
+                self.next_non_whitespace_token();
+                self.match_token(TokenType::Keyword(Keyword::In))?;
+                self.next_non_whitespace_token();
+                let iter = self.parse_condition()?;
+                self.next_non_whitespace_token();
+                let body = self.parse_block()?;
+                StatementKind::ForStmt(name, iter, Box::new(body))
+            }
+            TokenType::LeftCurly => return self.parse_block(),
+            TokenType::Semicolon => StatementKind::EmptyStmt,
+            _ => {
+                let expr = self.parse_expression()?;
+                self.next_non_whitespace_token();
+                match self.current_token()?.token_type {
+                    TokenType::Assign | TokenType::AssignOp(_) => self.parse_assignment(expr)?,
+                    _ => {
+                        self.match_token(TokenType::Semicolon)?;
+                        StatementKind::ExprStmt(expr)
                     }
                 }
-                _ => return Err(self.error("expected a type".to_string())),
+            }
+        };
+
+        Ok(Statement::new(kind, self.span_from(start)))
+    }
+
+    /// Parse the rest of `target = expr;` or a compound assignment such as `target += expr;`,
+    /// which is stored as `target = target + expr;`. The current token is the operator.
+    fn parse_assignment(&mut self, target: Expression) -> Result<StatementKind, Box<Diagnostic>> {
+        let mut place = &target;
+        while let ExpressionKind::FieldAccess(base, _) = &place.kind {
+            place = base;
+        }
+        if !matches!(place.kind, ExpressionKind::VarAccess(_)) {
+            return Err(Box::new(
+                Diagnostic::error(target.span, "cannot assign to this expression".to_string())
+                    .with_note("only variables and their fields can be assigned".to_string()),
+            ));
+        }
+
+        let op = match self.current_token()?.token_type {
+            TokenType::AssignOp(op) => Some(op),
+            _ => None,
+        };
+        self.next_non_whitespace_token();
+        let mut value = self.parse_expression()?;
+        if let Some(op) = op {
+            let span = target.span.to(value.span);
+            let kind = ExpressionKind::BinaryOp(op, Box::new(target.clone()), Box::new(value));
+            value = Expression::new(kind, span);
+        }
+        self.next_non_whitespace_token();
+        self.match_token(TokenType::Semicolon)?;
+
+        Ok(StatementKind::AssignStmt(target, value))
+    }
+
+    /// Parse `let name[: type] = expr;`, shared by local and global variables.
+    /// # Example:
+    /// ```sk
+    /// let x: int = 10;
+    /// ```
+    fn parse_binding(&mut self) -> Result<Binding, Box<Diagnostic>> {
+        self.match_token(TokenType::Keyword(Keyword::Let))?;
+        self.next_non_whitespace_token();
+        let name = self.parse_name()?;
+
+        self.next_non_whitespace_token();
+        let mut annotation = None;
+        if self.match_token(TokenType::Colon).is_ok() {
+            self.next_non_whitespace_token();
+            let t = self.parse_annotation()?;
+            if t.node == Type::Void {
+                return Err(self.error("variables cannot have type void".to_string()));
+            }
+            annotation = Some(t);
+            self.next_non_whitespace_token();
+        }
+
+        self.match_token(TokenType::Assign)?;
+        self.next_non_whitespace_token();
+        let value = self.parse_expression()?;
+        self.next_non_whitespace_token();
+        self.match_token(TokenType::Semicolon)?;
+
+        Ok((name, annotation, value))
+    }
+
+    /// Parse an if statement with an optional else branch, which may itself be an if statement.
+    /// `if let name = expr` binds the value of an optional instead of testing a condition.
+    fn parse_if(&mut self) -> Result<Statement, Box<Diagnostic>> {
+        self.match_token(TokenType::Keyword(Keyword::If))?;
+        let start = self.span();
+        self.next_non_whitespace_token();
+        let mut binding = None;
+        if self.match_token(TokenType::Keyword(Keyword::Let)).is_ok() {
+            self.next_non_whitespace_token();
+            binding = Some(self.parse_name()?);
+            self.next_non_whitespace_token();
+            self.match_token(TokenType::Assign)?;
+            self.next_non_whitespace_token();
+        }
+        let cond = self.parse_condition()?;
+        self.next_non_whitespace_token();
+        let then = self.parse_block()?;
+
+        let mut otherwise = None;
+        if let Some(token) = self.peek_non_whitespace_token() {
+            if token.token_type == TokenType::Keyword(Keyword::Else) {
+                self.next_non_whitespace_token();
+                self.next_non_whitespace_token();
+                let stmt = if self.match_token(TokenType::Keyword(Keyword::If)).is_ok() {
+                    self.parse_if()?
+                } else {
+                    self.parse_block()?
+                };
+                otherwise = Some(Box::new(stmt));
+            }
+        }
+
+        let kind = match binding {
+            Some(name) => StatementKind::IfLetStmt(name, cond, Box::new(then), otherwise),
+            None => StatementKind::IfStmt(cond, Box::new(then), otherwise),
+        };
+        Ok(Statement::new(kind, self.span_from(start)))
+    }
+
+    /// Parse an expression. The current token is left on the last token of the expression.
+    ///
+    /// Binary operators are parsed by precedence climbing, see `binary_precedence`. All binary
+    /// operators are left associative.
+    fn parse_expression(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        self.parse_binary(0)
+    }
+
+    /// Parse the expression in an `if`, `while` or `for` header, where struct literals are not
+    /// allowed unless parenthesised.
+    fn parse_condition(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        let restricted = self.no_struct_literal;
+        self.no_struct_literal = true;
+        let expr = self.parse_expression();
+        self.no_struct_literal = restricted;
+        expr
+    }
+
+    /// Parse an expression where struct literals are always allowed, such as inside parentheses.
+    fn parse_unrestricted_expression(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        let restricted = self.no_struct_literal;
+        self.no_struct_literal = false;
+        let expr = self.parse_expression();
+        self.no_struct_literal = restricted;
+        expr
+    }
+
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expression, Box<Diagnostic>> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(token) = self.peek_non_whitespace_token() {
+            let op = match token.token_type {
+                TokenType::Operator(op) => op,
+                TokenType::Pipe => Op::BitOr,
+                _ => break,
+            };
+            let precedence = match Self::binary_precedence(&op) {
+                Some(p) if p >= min_precedence => p,
+                _ => break,
             };
 
             self.next_non_whitespace_token();
-            return_type = Some(t);
+            self.next_non_whitespace_token();
+            let rhs = self.parse_binary(precedence + 1)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Expression::new(
+                ExpressionKind::BinaryOp(op, Box::new(lhs), Box::new(rhs)),
+                span,
+            );
         }
-        let block = self.parse_block()?;
 
-        Ok(Definition::FnDef(function_name, params, return_type, block))
+        Ok(lhs)
     }
 
-    fn parse_global(&mut self) -> Result<Definition, String> {
-        todo!("global function definition")
+    /// Binding power of binary operators, higher binds tighter. `None` for unary-only operators.
+    fn binary_precedence(op: &Op) -> Option<u8> {
+        match op {
+            Op::Or => Some(1),
+            Op::And => Some(2),
+            Op::Eq | Op::Neq => Some(3),
+            Op::Lt | Op::Gt | Op::Lte | Op::Gte => Some(4),
+            Op::Coalesce => Some(5),
+            Op::BitOr => Some(6),
+            Op::BitXor => Some(7),
+            Op::BitAnd => Some(8),
+            Op::Shl | Op::Shr => Some(9),
+            Op::Add | Op::Sub => Some(10),
+            Op::Mul | Op::Div | Op::Mod => Some(11),
+            Op::Not => None,
+        }
     }
 
-    fn parse_struct_def(&mut self) -> Result<Definition, String> {
-        todo!("struct definition")
+    /// Parse prefix `!` and `-`, which bind tighter than any binary operator.
+    fn parse_unary(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        let token = self.current_token()?;
+        match token.token_type {
+            TokenType::Operator(op @ (Op::Not | Op::Sub)) => {
+                self.next_non_whitespace_token();
+                let operand = self.parse_unary()?;
+                let span = token.span.to(operand.span);
+                Ok(Expression::new(
+                    ExpressionKind::UnaryOp(op, Box::new(operand)),
+                    span,
+                ))
+            }
+            _ => self.parse_postfix(),
+        }
     }
 
-    fn parse_block(&mut self) -> Result<Statement, String> {
-        self.match_token(TokenType::LeftCurly)?;
+    /// Parse a primary expression followed by any number of `.field` accesses and `(args)`
+    /// calls, so that `make()(1).f(2)` calls whatever each step evaluates to.
+    fn parse_postfix(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            let start = expr.span;
+            let kind = match self.peek_non_whitespace_token().map(|t| t.token_type) {
+                Some(TokenType::Dot) => {
+                    self.next_non_whitespace_token();
+                    self.next_non_whitespace_token();
+                    ExpressionKind::FieldAccess(Box::new(expr), self.parse_identifier()?)
+                }
+                Some(TokenType::LeftParen) => {
+                    self.next_non_whitespace_token();
+                    ExpressionKind::FnCall(Box::new(expr), self.parse_args()?)
+                }
+                _ => return Ok(expr),
+            };
+            expr = Expression::new(kind, self.span_from(start));
+        }
+    }
+
+    /// Parse literals, variables, struct literals and parenthesised expressions.
+    fn parse_primary(&mut self) -> Result<Expression, Box<Diagnostic>> {
+        let token = self.current_token()?;
+        let start = token.span;
+        let kind = match token.token_type {
+            TokenType::Number(n) => ExpressionKind::Literal(Literal::Int(n)),
+            TokenType::Float(n) => ExpressionKind::Literal(Literal::Double(n)),
+            TokenType::StrLiteral(s) => ExpressionKind::Literal(Literal::String(s)),
+            TokenType::Keyword(Keyword::True) => ExpressionKind::Literal(Literal::Bool(true)),
+            TokenType::Keyword(Keyword::False) => ExpressionKind::Literal(Literal::Bool(false)),
+            TokenType::Keyword(Keyword::None) => ExpressionKind::Literal(Literal::None),
+            TokenType::Identifier(name) => {
+                if !self.no_struct_literal {
+                    if let Some(TokenType::LeftCurly) =
+                        self.peek_non_whitespace_token().map(|t| t.token_type)
+                    {
+                        self.next_non_whitespace_token();
+                        let fields = self.parse_struct_fields()?;
+                        let kind = ExpressionKind::StructLiteral(Some(name), fields);
+                        return Ok(Expression::new(kind, self.span_from(start)));
+                    }
+                }
+                ExpressionKind::VarAccess(name)
+            }
+            TokenType::LeftCurly => {
+                ExpressionKind::StructLiteral(None, self.parse_struct_fields()?)
+            }
+            TokenType::Pipe | TokenType::Operator(Op::Or) => {
+                ExpressionKind::AnonFnDef(self.parse_anon_fn()?)
+            }
+            TokenType::LeftParen => {
+                self.next_non_whitespace_token();
+                let expr = self.parse_unrestricted_expression()?;
+                self.next_non_whitespace_token();
+                self.match_token(TokenType::RightParen)?;
+                // The parentheses are part of the expression, so that errors and hovers
+                // cover `(a + b)` and not just `a + b`.
+                expr.kind
+            }
+            t => return Err(self.error(format!("expected an expression, got {:?}", t))),
+        };
+
+        Ok(Expression::new(kind, self.span_from(start)))
+    }
+
+    /// Parse an anonymous function. The return type may be left out for `void` functions.
+    /// # Example:
+    /// ```sk
+    /// |x: int|: int {
+    ///     return x * factor;
+    /// }
+    /// ```
+    fn parse_anon_fn(&mut self) -> Result<AnonFn, Box<Diagnostic>> {
+        let params = self.parse_pipe_list(Self::parse_param)?;
+
         self.next_non_whitespace_token();
-        self.match_token(TokenType::RightCurly)?;
-        Ok(Statement::EmptyStmt)
+        let mut return_type = None;
+        if self.match_token(TokenType::Colon).is_ok() {
+            self.next_non_whitespace_token();
+            return_type = Some(self.parse_annotation()?);
+            self.next_non_whitespace_token();
+        }
+        let body = self.parse_block()?;
+
+        Ok(AnonFn {
+            params,
+            return_type,
+            body: Box::new(body),
+            captures: vec![],
+        })
+    }
+
+    /// Parse a parenthesised, comma separated argument list.
+    fn parse_args(&mut self) -> Result<Vec<Expression>, Box<Diagnostic>> {
+        self.match_token(TokenType::LeftParen)?;
+        let mut args = vec![];
+        loop {
+            self.next_non_whitespace_token();
+            if self.match_token(TokenType::RightParen).is_ok() {
+                break;
+            }
+
+            args.push(self.parse_unrestricted_expression()?);
+
+            self.next_non_whitespace_token();
+            if self.match_token(TokenType::Comma).is_err() {
+                self.match_token(TokenType::RightParen)?;
+                break;
+            }
+        }
+
+        Ok(args)
+    }
+
+    /// Parse the fields of a struct literal. Both `field: expr` and `field = expr` are accepted.
+    fn parse_struct_fields(&mut self) -> Result<Vec<(String, Expression)>, Box<Diagnostic>> {
+        self.match_token(TokenType::LeftCurly)?;
+        let mut fields: Vec<(String, Expression)> = vec![];
+        loop {
+            self.next_non_whitespace_token();
+            if self.match_token(TokenType::RightCurly).is_ok() {
+                break;
+            }
+
+            let field = self.parse_identifier()?;
+            if fields.iter().any(|(f, _)| *f == field) {
+                return Err(self.error(format!("field `{}` specified more than once", field)));
+            }
+
+            self.next_non_whitespace_token();
+            if self.match_token(TokenType::Colon).is_err() {
+                self.match_token(TokenType::Assign)?;
+            }
+            self.next_non_whitespace_token();
+            fields.push((field, self.parse_unrestricted_expression()?));
+
+            self.next_non_whitespace_token();
+            if self.match_token(TokenType::Comma).is_err() {
+                self.match_token(TokenType::RightCurly)?;
+                break;
+            }
+        }
+
+        Ok(fields)
+    }
+
+    fn parse_identifier(&mut self) -> Result<String, Box<Diagnostic>> {
+        match self.current_token()?.token_type {
+            TokenType::Identifier(name) => Ok(name),
+            t => Err(self.error(format!("expected identifier but got {:?}", t))),
+        }
+    }
+
+    /// Parse the identifier a declaration introduces, together with its span.
+    fn parse_name(&mut self) -> Result<Spanned<String>, Box<Diagnostic>> {
+        let span = self.span();
+        Ok(Spanned::new(self.parse_identifier()?, span))
     }
 
     fn end_of_tokens(&mut self) -> bool {
         self.token_index >= self.tokens.len()
     }
 
-    fn current_token(&mut self) -> Result<Token, String> {
+    fn current_token(&mut self) -> Result<Token, Box<Diagnostic>> {
         if !self.end_of_tokens() {
             Ok(self.tokens[self.token_index].clone())
         } else {
-            Err("Unexpected end of file".to_string())
+            Err(self.error("unexpected end of file".to_string()))
         }
     }
 
@@ -221,28 +908,42 @@ impl Parser {
 
     fn next_non_whitespace_token(&mut self) {
         self.next_token();
-        let whitespaces = vec![
-            TokenType::Whitespace,
-            TokenType::Newline,
-            TokenType::Comment,
-        ];
-        while whitespaces.iter().any(|t| {
-            if self.end_of_tokens() {
-                return false;
-            }
-            *t == self.current_token().unwrap().token_type
-        }) {
+        self.skip_whitespace();
+    }
+
+    /// Advance past whitespace, newlines and comments, staying put if the current token is
+    /// not one of them.
+    fn skip_whitespace(&mut self) {
+        while !self.end_of_tokens() && Self::is_whitespace(&self.tokens[self.token_index]) {
             self.next_token();
         }
     }
 
-    fn match_token(&mut self, token_type: TokenType) -> Result<(), String> {
+    /// Look at the next non-whitespace token without consuming anything.
+    fn peek_non_whitespace_token(&self) -> Option<Token> {
+        self.tokens
+            .iter()
+            .skip(self.token_index + 1)
+            .find(|token| !Self::is_whitespace(token))
+            .cloned()
+    }
+
+    fn is_whitespace(token: &Token) -> bool {
+        matches!(
+            token.token_type,
+            TokenType::Whitespace
+                | TokenType::Newline
+                | TokenType::Comment
+                | TokenType::DocComment(_)
+        )
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> Result<(), Box<Diagnostic>> {
         if let Ok(curr) = self.current_token() {
             return match (&curr.token_type, token_type.clone()) {
                 (TokenType::Identifier(_), TokenType::Identifier(_))
                 | (TokenType::Number(_), TokenType::Number(_))
-                | (TokenType::StrLiteral(_), TokenType::StrLiteral(_))
-                | (TokenType::Keyword(_), TokenType::Keyword(_)) => Ok(()),
+                | (TokenType::StrLiteral(_), TokenType::StrLiteral(_)) => Ok(()),
                 (curr_type, expected_type) if *curr_type == expected_type => Ok(()),
                 _ => {
                     let msg = format!(
@@ -254,27 +955,597 @@ impl Parser {
             };
         }
 
-        Ok(())
+        Err(self.error(format!(
+            "expected `{:?}`, but reached end of file",
+            token_type
+        )))
     }
 
-    fn keyword_is_type(&self, keyword: Keyword) -> bool {
-        return match keyword {
-            Keyword::Struct | Keyword::Type(_) => true,
-            _ => false,
-        };
+    /// Whether the next non-whitespace token is of the given type.
+    fn peek_is(&self, token_type: TokenType) -> bool {
+        self.peek_non_whitespace_token()
+            .is_some_and(|token| token.token_type == token_type)
     }
 
-    fn error(&mut self, msg: String) -> String {
-        if let Ok(token) = self.current_token() {
-            format!(
-                "Syntax error: {} at {}:{:?}:{:?}",
-                msg, self.file, &token.pos.line, token.pos.col
-            )
-        } else {
-            format!("Syntax error: {}", msg)
-        }
+    /// The span of the current token, or of the last token once the input has run out.
+    fn span(&self) -> Span {
+        self.tokens
+            .get(self.token_index)
+            .or(self.tokens.last())
+            .map(|token| token.span)
+            .unwrap_or_default()
+    }
+
+    /// The span from `start` to the end of the current token.
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.span())
+    }
+
+    /// A syntax error at the current token.
+    fn error(&self, msg: String) -> Box<Diagnostic> {
+        Box::new(Diagnostic::error(self.span(), msg))
     }
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+    use crate::source::SourceFile;
+    use crate::tokenizer::Tokenizer;
+
+    fn e(kind: ExpressionKind) -> Expression {
+        Expression::new(kind, Span::default())
+    }
+
+    fn st(kind: StatementKind) -> Statement {
+        Statement::new(kind, Span::default())
+    }
+
+    fn sp<T>(node: T) -> Spanned<T> {
+        Spanned::new(node, Span::default())
+    }
+
+    fn param(name: &str, t: Type) -> Param {
+        (sp(name.to_string()), sp(t))
+    }
+
+    fn parse(source: &str) -> Result<AST, Vec<Diagnostic>> {
+        let tokens = Tokenizer::new(0).tokenize(source).unwrap();
+        Parser::new(tokens).parse()
+    }
+
+    fn body(source: &str) -> Vec<Statement> {
+        let ast = parse(source).unwrap();
+        match &ast.definitions[0] {
+            Definition::FnDef(Function {
+                body:
+                    Statement {
+                        kind: StatementKind::Block(stmts),
+                        ..
+                    },
+                ..
+            }) => *stmts.clone(),
+            def => panic!("expected a function, got {:?}", def),
+        }
+    }
+
+    #[test]
+    fn parses_statements() {
+        let stmts = body(
+            "fn main() {
+                let x: int = 1;
+                let s = \"abc\";
+                x = 2;
+                if x { return; } else if s { x = 3; } else {}
+                while x { x = 4; }
+                for c in s { s = c; }
+                ;
+                return x;
+            }",
+        );
+        let x = || *var("x");
+        let s = || *var("s");
+        let block = |stmts: Vec<Statement>| Box::new(st(StatementKind::Block(Box::new(stmts))));
+        let assign = |n| st(StatementKind::AssignStmt(x(), *int(n)));
+
+        assert_eq!(stmts.len(), 8);
+        assert_eq!(
+            stmts[0],
+            st(StatementKind::DeclStmt(
+                sp("x".to_string()),
+                Some(sp(Type::Int)),
+                *int(1)
+            ))
+        );
+        assert_eq!(
+            stmts[1],
+            st(StatementKind::DeclStmt(
+                sp("s".to_string()),
+                None,
+                e(ExpressionKind::Literal(Literal::String("abc".to_string())))
+            ))
+        );
+        assert_eq!(stmts[2], assign(2));
+        assert_eq!(
+            stmts[3],
+            st(StatementKind::IfStmt(
+                x(),
+                block(vec![st(StatementKind::RetStmt(None))]),
+                Some(Box::new(st(StatementKind::IfStmt(
+                    s(),
+                    block(vec![assign(3)]),
+                    Some(block(vec![]))
+                ))))
+            ))
+        );
+        assert_eq!(
+            stmts[4],
+            st(StatementKind::WhileStmt(x(), block(vec![assign(4)])))
+        );
+        assert!(matches!(stmts[5].kind, StatementKind::ForStmt(ref c, _, _) if c.node == "c"));
+        assert_eq!(stmts[6], st(StatementKind::EmptyStmt));
+        assert_eq!(stmts[7], st(StatementKind::RetStmt(Some(x()))));
+    }
+
+    fn expr(source: &str) -> Expression {
+        match &body(&format!("fn main() {{ let e = {}; }}", source))[0].kind {
+            StatementKind::DeclStmt(_, _, expr) => expr.clone(),
+            stmt => panic!("expected a declaration, got {:?}", stmt),
+        }
+    }
+
+    fn int(n: i64) -> Box<Expression> {
+        Box::new(e(ExpressionKind::Literal(Literal::Int(n))))
+    }
+
+    fn var(name: &str) -> Box<Expression> {
+        Box::new(e(ExpressionKind::VarAccess(name.to_string())))
+    }
+
+    fn bin(op: Op, lhs: Box<Expression>, rhs: Box<Expression>) -> Box<Expression> {
+        Box::new(e(ExpressionKind::BinaryOp(op, lhs, rhs)))
+    }
+
+    fn unary(op: Op, operand: Box<Expression>) -> Box<Expression> {
+        Box::new(e(ExpressionKind::UnaryOp(op, operand)))
+    }
+
+    fn field(base: Box<Expression>, name: &str) -> Box<Expression> {
+        Box::new(e(ExpressionKind::FieldAccess(base, name.to_string())))
+    }
+
+    #[test]
+    fn binary_operators_respect_precedence() {
+        assert_eq!(
+            expr("1 + 2 * 3 - 4"),
+            *bin(
+                Op::Sub,
+                bin(Op::Add, int(1), bin(Op::Mul, int(2), int(3))),
+                int(4)
+            )
+        );
+        assert_eq!(
+            expr("a < 1 || b >= 2 && c != 3"),
+            *bin(
+                Op::Or,
+                bin(Op::Lt, var("a"), int(1)),
+                bin(
+                    Op::And,
+                    bin(Op::Gte, var("b"), int(2)),
+                    bin(Op::Neq, var("c"), int(3))
+                )
+            )
+        );
+        assert_eq!(
+            expr("1 | 2 ^ 3 & 4 << 5 + 6"),
+            *bin(
+                Op::BitOr,
+                int(1),
+                bin(
+                    Op::BitXor,
+                    int(2),
+                    bin(
+                        Op::BitAnd,
+                        int(3),
+                        bin(Op::Shl, int(4), bin(Op::Add, int(5), int(6)))
+                    )
+                )
+            )
+        );
+        assert_eq!(
+            expr("a & b == c"),
+            *bin(Op::Eq, bin(Op::BitAnd, var("a"), var("b")), var("c"))
+        );
+    }
+
+    #[test]
+    fn unary_and_parenthesised_expressions() {
+        assert_eq!(
+            expr("-(1 - 2) % 3"),
+            *bin(
+                Op::Mod,
+                unary(Op::Sub, bin(Op::Sub, int(1), int(2))),
+                int(3)
+            )
+        );
+        assert_eq!(expr("!!a"), *unary(Op::Not, unary(Op::Not, var("a"))));
+        assert_eq!(
+            expr("-2.5e1"),
+            *unary(
+                Op::Sub,
+                Box::new(e(ExpressionKind::Literal(Literal::Double(25.0))))
+            )
+        );
+    }
+
+    #[test]
+    fn call_expressions() {
+        let call = |callee, args| e(ExpressionKind::FnCall(callee, args));
+        let fib = |n| Box::new(call(var("fib"), vec![*bin(Op::Sub, var("n"), int(n))]));
+        assert_eq!(expr("fib(n-1) + fib(n-2)"), *bin(Op::Add, fib(1), fib(2)));
+        assert_eq!(expr("f()"), call(var("f"), vec![]));
+
+        // Anything can be called: a call's result, a field or a parenthesised expression.
+        let curried = call(Box::new(call(var("yeet"), vec![*int(3)])), vec![*int(7)]);
+        assert_eq!(expr("yeet(3)(7)"), curried);
+        let field = e(ExpressionKind::FieldAccess(var("s"), "f".to_string()));
+        assert_eq!(expr("s.f(1)"), call(Box::new(field), vec![*int(1)]));
+        let nested = expr("(f)()");
+        assert_eq!(nested, call(var("f"), vec![]));
+        // `fn main() { let e = ` comes before the expression.
+        let ExpressionKind::FnCall(callee, _) = nested.kind else {
+            unreachable!()
+        };
+        assert_eq!((callee.span.start, callee.span.end), (20, 23));
+        assert_eq!((nested.span.start, nested.span.end), (20, 25));
+    }
+
+    #[test]
+    fn struct_definitions_keep_field_order() {
+        let ast = parse("struct Pos { y: int, x: int, name: str, next: Pos, }").unwrap();
+        let Definition::StructDef(s) = &ast.definitions[0] else {
+            panic!("expected a struct, got {:?}", ast.definitions[0]);
+        };
+        assert_eq!(s.name, "Pos");
+        assert_eq!(
+            s.fields,
+            vec![
+                param("y", Type::Int),
+                param("x", Type::Int),
+                param("name", Type::String),
+                param("next", Type::Struct("Pos".to_string())),
+            ]
+        );
+        assert!(parse("struct Pos { x: int, x: int }").is_err());
+    }
+
+    #[test]
+    fn struct_literals_and_field_access() {
+        let fields = vec![("y".to_string(), *int(1)), ("x".to_string(), *int(2))];
+        assert_eq!(
+            expr("Pos { y: 1, x: 2 }"),
+            e(ExpressionKind::StructLiteral(
+                Some("Pos".to_string()),
+                fields.clone()
+            ))
+        );
+        assert_eq!(
+            expr("{ y = 1, x = 2 }"),
+            e(ExpressionKind::StructLiteral(None, fields))
+        );
+        assert_eq!(
+            expr("-a.b.c"),
+            *unary(Op::Sub, field(field(var("a"), "b"), "c"))
+        );
+    }
+
+    #[test]
+    fn assignments() {
+        let stmts = body("fn main() { x = 1; a.b -= 2; x == 1; }");
+        assert_eq!(stmts[0], st(StatementKind::AssignStmt(*var("x"), *int(1))));
+        let a_b = || field(var("a"), "b");
+        assert_eq!(
+            stmts[1],
+            st(StatementKind::AssignStmt(
+                *a_b(),
+                *bin(Op::Sub, a_b(), int(2))
+            ))
+        );
+        assert_eq!(
+            stmts[2],
+            st(StatementKind::ExprStmt(*bin(Op::Eq, var("x"), int(1))))
+        );
+
+        let errors = parse("fn main() { f() = 1; 1 += 2; }").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "cannot assign to this expression");
+    }
+
+    #[test]
+    fn struct_literals_are_not_parsed_in_conditions() {
+        let stmts = body("fn main() { if x { a.b = 1; } }");
+        assert_eq!(
+            stmts[0],
+            st(StatementKind::IfStmt(
+                *var("x"),
+                Box::new(st(StatementKind::Block(Box::new(vec![st(
+                    StatementKind::AssignStmt(*field(var("a"), "b"), *int(1))
+                )])))),
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn global_definitions() {
+        let ast = parse("let x: int = 1 + 2;\nlet y = x;").unwrap();
+        assert_eq!(
+            ast.definitions,
+            vec![
+                Definition::GlobalDef(
+                    sp("x".to_string()),
+                    Some(sp(Type::Int)),
+                    *bin(Op::Add, int(1), int(2)),
+                    Span {
+                        file_id: 0,
+                        start: 0,
+                        end: 19
+                    },
+                    None
+                ),
+                Definition::GlobalDef(
+                    sp("y".to_string()),
+                    None,
+                    *var("x"),
+                    Span {
+                        file_id: 0,
+                        start: 20,
+                        end: 30
+                    },
+                    None
+                ),
+            ]
+        );
+        assert!(parse("let x;").is_err());
+    }
+
+    #[test]
+    fn declarations_record_name_and_type_spans() {
+        let source = "struct P { x: int? }\n\
+                      fn f(p: P) -> |int|: int {\n\
+                          let g: |int|: int = |n: int|: int { return n; };\n\
+                          for c in \"ab\" {}\n\
+                          if let y = p.x {}\n\
+                          return g;\n\
+                      }";
+        let ast = parse(source).unwrap();
+        let text = |span: Span| &source[span.start..span.end];
+
+        let Definition::StructDef(s) = &ast.definitions[0] else {
+            panic!("expected a struct, got {:?}", ast.definitions[0]);
+        };
+        let (x, t) = &s.fields[0];
+        assert_eq!((text(x.span), text(t.span)), ("x", "int?"));
+
+        let Definition::FnDef(f) = &ast.definitions[1] else {
+            panic!("expected a function, got {:?}", ast.definitions[1]);
+        };
+        let (p, t) = &f.params[0];
+        assert_eq!((text(p.span), text(t.span)), ("p", "P"));
+        assert_eq!(
+            f.return_type.as_ref().map(|t| text(t.span)),
+            Some("|int|: int")
+        );
+
+        let StatementKind::Block(stmts) = &f.body.kind else {
+            panic!("expected a block, got {:?}", f.body);
+        };
+        let StatementKind::DeclStmt(g, Some(t), closure) = &stmts[0].kind else {
+            panic!("expected a declaration, got {:?}", stmts[0]);
+        };
+        assert_eq!((text(g.span), text(t.span)), ("g", "|int|: int"));
+        let ExpressionKind::AnonFnDef(closure) = &closure.kind else {
+            panic!("expected an anonymous function, got {:?}", closure);
+        };
+        let (n, t) = &closure.params[0];
+        assert_eq!((text(n.span), text(t.span)), ("n", "int"));
+        let StatementKind::ForStmt(c, ..) = &stmts[1].kind else {
+            panic!("expected a loop, got {:?}", stmts[1]);
+        };
+        assert_eq!(text(c.span), "c");
+        let StatementKind::IfLetStmt(y, ..) = &stmts[2].kind else {
+            panic!("expected an if let, got {:?}", stmts[2]);
+        };
+        assert_eq!(text(y.span), "y");
+    }
+
+    #[test]
+    fn doc_comments_attach_to_definitions() {
+        let ast = parse(
+            "/// The origin.\nlet o = 0;\n\n/// A point.\n/// In 2D.\n// not docs\nstruct P { x: int }\n\
+             fn f() { /// ignored\n }",
+        )
+        .unwrap();
+        let docs: Vec<Option<&str>> = ast.definitions.iter().map(|d| d.doc()).collect();
+        assert_eq!(
+            docs,
+            vec![Some("The origin."), Some("A point.\nIn 2D."), None]
+        );
+    }
+
+    #[test]
+    fn function_types() {
+        let ast =
+            parse("fn yeet(f: |str, int|: bool, g: || : void) -> |int|: |int|: int {}").unwrap();
+        let Definition::FnDef(f) = &ast.definitions[0] else {
+            panic!("expected a function, got {:?}", ast.definitions[0]);
+        };
+        assert_eq!(
+            f.params,
+            vec![
+                param(
+                    "f",
+                    Type::Function(vec![Type::String, Type::Int], Box::new(Type::Bool))
+                ),
+                param("g", Type::Function(vec![], Box::new(Type::Void))),
+            ]
+        );
+        let int_to_int = Type::Function(vec![Type::Int], Box::new(Type::Int));
+        assert_eq!(
+            f.return_type,
+            Some(sp(Type::Function(vec![Type::Int], Box::new(int_to_int))))
+        );
+        assert!(parse("fn f(g: |void|: int) {}").is_err());
+        assert!(parse("fn f(g: |int|) {}").is_err());
+    }
+
+    #[test]
+    fn optional_types_and_if_let() {
+        let ast = parse("fn find(p: Pos?, f: |int|: int?) -> bool? {}").unwrap();
+        let Definition::FnDef(f) = &ast.definitions[0] else {
+            panic!("expected a function, got {:?}", ast.definitions[0]);
+        };
+        let optional = |t| Type::Optional(Box::new(t));
+        assert_eq!(
+            f.params,
+            vec![
+                param("p", optional(Type::Struct("Pos".to_string()))),
+                param(
+                    "f",
+                    Type::Function(vec![Type::Int], Box::new(optional(Type::Int)))
+                ),
+            ]
+        );
+        assert_eq!(f.return_type, Some(sp(optional(Type::Bool))));
+        assert!(parse("fn f(x: void?) {}").is_err());
+        assert!(parse("fn f(x: (int) {}").is_err());
+
+        // Every type prints as text that parses back into it.
+        let function = Type::Function(vec![Type::Int], Box::new(optional(Type::Int)));
+        for t in [
+            optional(function.clone()),
+            Type::Function(vec![optional(function.clone())], Box::new(function)),
+            optional(Type::Struct("Pos".to_string())),
+        ] {
+            let text = t.to_string();
+            let tokens = Tokenizer::new(0).tokenize(&text).unwrap();
+            assert_eq!(Parser::new(tokens).parse_type_only(), Ok(t), "{}", text);
+        }
+
+        assert_eq!(
+            body("fn main() { if let x = a ?? none { } else { true; } }"),
+            vec![st(StatementKind::IfLetStmt(
+                sp("x".to_string()),
+                *bin(
+                    Op::Coalesce,
+                    var("a"),
+                    Box::new(e(ExpressionKind::Literal(Literal::None)))
+                ),
+                Box::new(st(StatementKind::Block(Box::default()))),
+                Some(Box::new(st(StatementKind::Block(Box::new(vec![st(
+                    StatementKind::ExprStmt(e(ExpressionKind::Literal(Literal::Bool(true))))
+                )]))))),
+            ))]
+        );
+        // `??` binds tighter than comparisons and looser than arithmetic.
+        assert_eq!(
+            expr("a ?? 1 + 2 < 4"),
+            *bin(
+                Op::Lt,
+                bin(Op::Coalesce, var("a"), bin(Op::Add, int(1), int(2))),
+                int(4)
+            )
+        );
+    }
+
+    #[test]
+    fn anonymous_functions_record_captures() {
+        let stmts = body(
+            "fn yeet(factor: int) {
+                let unused = 0;
+                let f = |x: int, y: int|: int {
+                    let z = x;
+                    let g = || { print(\"{}\", factor + z + y); };
+                    return x * factor;
+                };
+            }",
+        );
+        let StatementKind::DeclStmt(_, _, f) = &stmts[1].kind else {
+            panic!("expected a declaration, got {:?}", stmts[1]);
+        };
+        let ExpressionKind::AnonFnDef(f) = &f.kind else {
+            panic!("expected an anonymous function, got {:?}", f);
+        };
+        assert_eq!(f.params, vec![param("x", Type::Int), param("y", Type::Int)]);
+        assert_eq!(f.return_type, Some(sp(Type::Int)));
+        assert_eq!(f.captures, vec!["factor".to_string()]);
+
+        let StatementKind::Block(stmts) = &f.body.kind else {
+            panic!("expected a block, got {:?}", f.body);
+        };
+        let StatementKind::DeclStmt(_, _, g) = &stmts[1].kind else {
+            panic!("expected a declaration, got {:?}", stmts[1]);
+        };
+        let ExpressionKind::AnonFnDef(g) = &g.kind else {
+            panic!("expected an anonymous function, got {:?}", g);
+        };
+        assert_eq!(g.return_type, None);
+        assert_eq!(
+            g.captures,
+            vec!["factor".to_string(), "z".to_string(), "y".to_string()]
+        );
+    }
+
+    #[test]
+    fn unterminated_block_is_an_error() {
+        let errors = parse("fn main() { if x { let x = 1;").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].secondary[0].message, "unclosed delimiter");
+    }
+
+    #[test]
+    fn recovers_to_report_every_error() {
+        let source = "fn main() {
+                let x = ;
+                if x { return 1 }
+                let y = 2;
+                y = );
+            }
+            fn (x: int) {}
+            struct P { x: int }
+            let g = 1 +;";
+        let errors = parse(source).unwrap_err();
+        let file = SourceFile::new("test.sk".to_string(), source.to_string());
+        let lines: Vec<i64> = errors
+            .iter()
+            .map(|d| file.position(d.primary.span.start).line)
+            .collect();
+        assert_eq!(lines, vec![2, 3, 5, 7, 9]);
+    }
+
+    #[test]
+    fn nodes_record_their_spans() {
+        let source = "fn main() {\n    let p = a.b + f(1, 2);\n}";
+        let ast = parse(source).unwrap();
+        let Definition::FnDef(main) = &ast.definitions[0] else {
+            panic!("expected a function, got {:?}", ast.definitions[0]);
+        };
+        let text = |span: Span| &source[span.start..span.end];
+        assert_eq!(text(main.span), source);
+        assert_eq!(text(main.name_span), "main");
+
+        let StatementKind::Block(stmts) = &main.body.kind else {
+            panic!("expected a block, got {:?}", main.body);
+        };
+        assert_eq!(text(stmts[0].span), "let p = a.b + f(1, 2);");
+        let StatementKind::DeclStmt(_, _, value) = &stmts[0].kind else {
+            panic!("expected a declaration, got {:?}", stmts[0]);
+        };
+        assert_eq!(text(value.span), "a.b + f(1, 2)");
+        let ExpressionKind::BinaryOp(_, lhs, rhs) = &value.kind else {
+            panic!("expected a binary operation, got {:?}", value);
+        };
+        assert_eq!(text(lhs.span), "a.b");
+        assert_eq!(text(rhs.span), "f(1, 2)");
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::mem;
+
+use crate::captures;
+use crate::checker::Checker;
+use crate::diagnostic::Diagnostic;
+use crate::interpreter::{Interpreter, Limits, RuntimeError, Value};
+use crate::parser::Parser;
+use crate::source::SourceMap;
+use crate::tokenizer::Tokenizer;
+use crate::types::{
+    Definition, Expression, Function, Item, Span, Spanned, Statement, StatementKind, TokenType,
+    Type, AST,
+};
+use crate::Diagnostics;
+
+/// The function an expression or statements are run in. No program can define a function by
+/// this name.
+const INPUT_FN: &str = "<input>";
+
+/// What one input to the REPL is.
+enum Input {
+    Items(Vec<Item>),
+    Expression(Expression),
+}
+
+/// An interactive session. The definitions entered so far make up a program, which every
+/// expression and statement is checked and run in. A global is initialised once, when it is
+/// defined, and keeps the values assigned to it from one input to the next.
+/// # Example:
+/// ```
+/// let mut repl = slang::repl::Repl::new();
+/// repl.eval("fn twice(n: int) -> int { return n * 2; }").unwrap();
+/// assert_eq!(repl.eval("twice(21)").unwrap(), "42: int");
+/// assert_eq!(repl.eval(":type twice").unwrap(), "|int|: int");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Repl {
+    /// Every input so far, so that diagnostics can quote them.
+    sources: SourceMap,
+    definitions: Vec<Definition>,
+    globals: HashMap<String, Value>,
+    limits: Limits,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl {
+            sources: SourceMap::new(),
+            definitions: vec![],
+            globals: HashMap::new(),
+            limits: Limits::default(),
+        }
+    }
+
+    /// Run each input within `limits` instead of the defaults. Going over them is reported
+    /// like any other runtime error, so an input that loops forever ends the input, not the
+    /// session.
+    pub fn with_limits(mut self, limits: Limits) -> Repl {
+        self.limits = limits;
+        self
+    }
+
+    /// Whether `input` is ready to run, or the user is still typing it because a bracket is
+    /// left open. Input that does not tokenize is complete, so that its errors are reported.
+    pub fn is_complete(input: &str) -> bool {
+        let Ok(tokens) = Tokenizer::new(0).tokenize(input) else {
+            return true;
+        };
+        let mut depth = 0;
+        for token in tokens {
+            match token.token_type {
+                TokenType::LeftCurly | TokenType::LeftParen | TokenType::LeftBracket => depth += 1,
+                TokenType::RightCurly | TokenType::RightParen | TokenType::RightBracket => {
+                    depth -= 1
+                }
+                _ => {}
+            }
+        }
+        depth <= 0
+    }
+
+    /// Run one input and return what to print, or the errors it has.
+    ///
+    /// An expression, optionally followed by `;`, is evaluated and printed with its type. Any
+    /// other input is a mix of `fn`, `struct` and `let` definitions, which are added to the
+    /// session and replace earlier definitions of the same name, and statements, which are
+    /// run. `:type expr` only prints the type and `:ast input` prints the syntax tree.
+    pub fn eval(&mut self, input: &str) -> Result<String, String> {
+        let input = input.trim();
+        if let Some(command) = input.strip_prefix(':') {
+            let (command, arg) = command
+                .split_once(char::is_whitespace)
+                .unwrap_or((command, ""));
+            return match command {
+                "type" => match self.parse(arg)? {
+                    Input::Expression(expr) => Ok(self.compile(expr)?.1.to_string()),
+                    Input::Items(_) => Err("error: expected an expression".to_string()),
+                },
+                "ast" => match self.parse(arg)? {
+                    Input::Items(items) => Ok(format!("{:#?}", items)),
+                    Input::Expression(expr) => Ok(format!("{:#?}", expr)),
+                },
+                _ => Err(format!(
+                    "error: unknown command `:{}`, expected `:type` or `:ast`",
+                    command
+                )),
+            };
+        }
+        if input.is_empty() {
+            return Ok(String::new());
+        }
+
+        let expr = match self.parse(input)? {
+            Input::Items(items) => {
+                self.run_items(items)?;
+                return Ok(String::new());
+            }
+            Input::Expression(expr) => expr,
+        };
+        let (ast, t) = self.compile(expr)?;
+        let value = self.call(&ast)?;
+        Ok(match value {
+            Value::Void => String::new(),
+            Value::Str(s) => format!("{:?}: {}", s, t),
+            value => format!("{}: {}", value, t),
+        })
+    }
+
+    /// Add the definitions in `items` to the session and run its statements, in order.
+    /// Definitions next to each other are added together, so that they can use each other. An
+    /// error stops the input, keeping the effects of what came before it.
+    fn run_items(&mut self, items: Vec<Item>) -> Result<(), String> {
+        let mut definitions = vec![];
+        let mut statements = vec![];
+        for item in items {
+            match item {
+                Item::Definition(def) => {
+                    if !statements.is_empty() {
+                        self.run(mem::take(&mut statements))?;
+                    }
+                    definitions.push(def);
+                }
+                Item::Statement(stmt) => {
+                    if !definitions.is_empty() {
+                        self.define(mem::take(&mut definitions))?;
+                    }
+                    statements.push(stmt);
+                }
+            }
+        }
+        if !definitions.is_empty() {
+            self.define(definitions)?;
+        }
+        if !statements.is_empty() {
+            self.run(statements)?;
+        }
+        Ok(())
+    }
+
+    /// Add `definitions` to the session if the program still type checks with them and the
+    /// globals among them initialise, in the order they are given.
+    fn define(&mut self, definitions: Vec<Definition>) -> Result<(), String> {
+        let mut ast = AST::new();
+        ast.definitions = self.definitions.clone();
+        let mut globals = vec![];
+        for def in definitions {
+            if let Definition::GlobalDef(name, ..) = &def {
+                globals.push(name.node.clone());
+            }
+            let previous = ast.definitions.iter().position(|d| {
+                mem::discriminant(d) == mem::discriminant(&def) && d.name() == def.name()
+            });
+            match previous {
+                Some(i) => ast.definitions[i] = def,
+                None => ast.definitions.push(def),
+            }
+        }
+        captures::analyze(&mut ast);
+
+        if let Err(errors) = Checker::new().check(&ast) {
+            return Err(self.render(errors.into_iter().map(Diagnostic::from).collect()));
+        }
+        let mut interpreter = Interpreter::new(&ast)
+            .with_limits(self.limits)
+            .with_globals(self.globals.clone());
+        for name in &globals {
+            interpreter
+                .initialize_global(name)
+                .map_err(|err| self.runtime_error(err))?;
+        }
+        self.globals = interpreter.globals().clone();
+        self.definitions = ast.definitions;
+        Ok(())
+    }
+
+    /// Type check `statements` in the session's program and run them.
+    fn run(&mut self, statements: Vec<Statement>) -> Result<(), String> {
+        let span = statements[0].span.to(statements[statements.len() - 1].span);
+        let mut ast = AST::new();
+        ast.definitions = self.definitions.clone();
+        ast.definitions.push(Self::input_fn(statements, None, span));
+        captures::analyze(&mut ast);
+
+        if let Err(errors) = Checker::new().check(&ast) {
+            return Err(self.render(errors.into_iter().map(Diagnostic::from).collect()));
+        }
+        self.call(&ast)?;
+        Ok(())
+    }
+
+    /// Call the input function of `ast` with the session's globals. What it assigns to them is
+    /// kept, even when it fails part way through.
+    fn call(&mut self, ast: &AST) -> Result<Value, String> {
+        let mut interpreter = Interpreter::new(ast)
+            .with_limits(self.limits)
+            .with_globals(mem::take(&mut self.globals));
+        let result = interpreter.call_function(INPUT_FN, vec![]);
+        self.globals = interpreter.globals().clone();
+        result.map_err(|err| self.runtime_error(err))
+    }
+
+    /// Type check `expr` and return the session's program with a function returning it, along
+    /// with its type.
+    fn compile(&mut self, expr: Expression) -> Result<(AST, Type), String> {
+        let mut ast = AST::new();
+        ast.definitions = self.definitions.clone();
+
+        let mut checker = Checker::new();
+        let checked = checker
+            .check(&ast)
+            .and_then(|_| checker.check_global_expression(&expr));
+        let t = match checked {
+            Ok(t) => t,
+            Err(errors) => {
+                return Err(self.render(errors.into_iter().map(Diagnostic::from).collect()))
+            }
+        };
+
+        let span = expr.span;
+        let body = vec![Statement::new(StatementKind::RetStmt(Some(expr)), span)];
+        let return_type = (t != Type::Void).then(|| t.clone());
+        ast.definitions
+            .push(Self::input_fn(body, return_type, span));
+        captures::analyze(&mut ast);
+
+        Ok((ast, t))
+    }
+
+    /// The function that runs an input, with the statements of `body`.
+    fn input_fn(body: Vec<Statement>, return_type: Option<Type>, span: Span) -> Definition {
+        Definition::FnDef(Function {
+            name: INPUT_FN.to_string(),
+            params: vec![],
+            return_type: return_type.map(|t| Spanned::new(t, span)),
+            body: Statement::new(StatementKind::Block(Box::new(body)), span),
+            span,
+            name_span: span,
+            doc: None,
+        })
+    }
+
+    /// Parse `input` as an expression if it is one, and otherwise as definitions and
+    /// statements.
+    fn parse(&mut self, input: &str) -> Result<Input, String> {
+        let name = format!("<input {}>", self.sources.files().len() + 1);
+        let file_id = self.sources.add(name, input.to_string());
+        let tokens = match Tokenizer::new(file_id).tokenize(input) {
+            Ok(tokens) => tokens,
+            Err(diagnostics) => return Err(self.render(diagnostics)),
+        };
+
+        // An expression may end with a `;` like an expression statement.
+        let last = tokens.iter().rposition(|token| {
+            !matches!(
+                token.token_type,
+                TokenType::Whitespace
+                    | TokenType::Newline
+                    | TokenType::Comment
+                    | TokenType::DocComment(_)
+            )
+        });
+        let mut expression = tokens.clone();
+        if let Some(i) = last {
+            if tokens[i].token_type == TokenType::Semicolon {
+                expression.truncate(i);
+            }
+        }
+        if let Ok(expr) = Parser::new(expression).parse_expression_only() {
+            return Ok(Input::Expression(expr));
+        }
+        match Parser::new(tokens).parse_items() {
+            Ok(items) => Ok(Input::Items(items)),
+            Err(diagnostics) => Err(self.render(diagnostics)),
+        }
+    }
+
+    fn runtime_error(&self, err: RuntimeError) -> String {
+        match err.diagnostic() {
+            Some(diagnostic) => self.render(vec![diagnostic]),
+            None => err.to_string(),
+        }
+    }
+
+    fn render(&self, diagnostics: Vec<Diagnostic>) -> String {
+        Diagnostics::new(self.sources.clone(), diagnostics).to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn definitions_persist_between_inputs() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval("struct P { x: int, y: int }"), Ok(String::new()));
+        assert_eq!(
+            repl.eval("let origin: P = { x = 0, y = 0 };"),
+            Ok(String::new())
+        );
+        let input = "fn shift(p: P, by: int) -> P {\n    return { x = p.x + by, y = p.y };\n}";
+        assert_eq!(repl.eval(input), Ok(String::new()));
+
+        assert_eq!(
+            repl.eval("shift(origin, 2)").as_deref(),
+            Ok("P { x: 2, y: 0 }: P")
+        );
+        assert_eq!(
+            repl.eval("format(\"{}!\", 1);").as_deref(),
+            Ok("\"1!\": str")
+        );
+        assert_eq!(repl.eval("print(\"hi\")"), Ok(String::new()));
+
+        // Redefining a name replaces it, as long as everything still type checks.
+        assert_eq!(
+            repl.eval("let origin: P = { x = 5, y = 5 };"),
+            Ok(String::new())
+        );
+        assert_eq!(repl.eval("origin.x + 1").as_deref(), Ok("6: int"));
+        assert!(repl.eval("struct P { x: int }").is_err());
+        assert_eq!(repl.eval("shift(origin, -5).y").as_deref(), Ok("5: int"));
+    }
+
+    #[test]
+    fn globals_keep_their_values() {
+        let mut repl = Repl::new();
+        repl.eval("let n = 0;").unwrap();
+        repl.eval("fn bump() -> int { n = n + 1; return n; }")
+            .unwrap();
+        repl.eval("let first = bump();").unwrap();
+        assert_eq!(repl.eval("first").as_deref(), Ok("1: int"));
+        assert_eq!(repl.eval("n").as_deref(), Ok("1: int"));
+        assert_eq!(repl.eval("bump()").as_deref(), Ok("2: int"));
+        assert_eq!(repl.eval("first + n").as_deref(), Ok("3: int"));
+
+        // A global that fails to initialise is not defined.
+        assert!(repl.eval("let broken = 1 / (n - 2);").is_err());
+        assert!(repl.eval("broken").is_err());
+    }
+
+    #[test]
+    fn statements_run_with_the_globals() {
+        let mut repl = Repl::new();
+        repl.eval("let i = 0; let total = 0;").unwrap();
+        assert_eq!(
+            repl.eval("while i < 4 { total += i; i += 1; }"),
+            Ok(String::new())
+        );
+        assert_eq!(repl.eval("total").as_deref(), Ok("6: int"));
+        repl.eval("let k = 3; total = total + k; let m = total;")
+            .unwrap();
+        assert_eq!(repl.eval("m").as_deref(), Ok("9: int"));
+        assert_eq!(repl.eval("{ let k = 1; i = k; }"), Ok(String::new()));
+        assert_eq!(repl.eval("i + k").as_deref(), Ok("4: int"));
+
+        assert!(repl.eval("i = \"a\";").is_err());
+        // What ran before an error is kept.
+        assert!(repl.eval("i = 0; i = 1 / i;").is_err());
+        assert_eq!(repl.eval("i").as_deref(), Ok("0: int"));
+    }
+
+    #[test]
+    fn meta_commands_and_errors() {
+        let mut repl = Repl::new();
+        repl.eval("fn id(n: int?) -> int? { return n; }").unwrap();
+        assert_eq!(repl.eval(":type id").as_deref(), Ok("|int?|: int?"));
+        assert_eq!(repl.eval(":type id(1) ?? 2").as_deref(), Ok("int"));
+        assert!(repl.eval(":ast 1 + 2").unwrap().contains("BinaryOp"));
+        assert!(repl.eval(":run").is_err());
+
+        let err = repl.eval("id(\"a\")").unwrap_err();
+        assert!(err.starts_with("error: expected `int?`, found `str`"));
+        assert!(err.contains(" --> <input 5>:1:4"));
+        let err = repl.eval("1 / (id(0) ?? 0)").unwrap_err();
+        assert!(err.starts_with("error: division by zero"));
+        assert!(repl.eval("1 +").is_err());
+        assert!(repl.eval("fn broken( {}").is_err());
+    }
+
+    #[test]
+    fn limits_stop_inputs_but_not_the_session() {
+        let limits = Limits {
+            fuel: Some(1000),
+            ..Limits::default()
+        };
+        let mut repl = Repl::new().with_limits(limits);
+        repl.eval("fn spin() -> int { while true {} return 0; }")
+            .unwrap();
+        let err = repl.eval("spin()").unwrap_err();
+        assert!(err.starts_with("error: ran out of fuel after 1000 steps"));
+        assert_eq!(repl.eval("1 + 1").as_deref(), Ok("2: int"));
+    }
+
+    #[test]
+    fn open_brackets_continue_the_input() {
+        assert!(Repl::is_complete("1 + 2"));
+        assert!(!Repl::is_complete("fn f() {"));
+        assert!(!Repl::is_complete("fn f() {\n    print(\"}\", (1"));
+        assert!(Repl::is_complete("fn f() {\n}"));
+        assert!(Repl::is_complete("}"));
+        assert!(Repl::is_complete("\"unterminated {"));
+    }
+}
@@ -0,0 +1,97 @@
+use crate::types::{Position, Span};
+
+/// A source file and the offsets its lines start at.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: String, text: String) -> SourceFile {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceFile {
+            name,
+            text,
+            line_starts,
+        }
+    }
+
+    /// The line and column of a byte offset.
+    pub fn position(&self, offset: usize) -> Position {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.line_starts[line];
+        let col = self.text[start..offset].chars().count();
+        Position {
+            line: line as i64 + 1,
+            col: col as i64 + 1,
+        }
+    }
+
+    /// The text of a line, starting at 1, without its line break.
+    pub fn line(&self, line: i64) -> Option<&str> {
+        let index = usize::try_from(line - 1).ok()?;
+        let start = *self.line_starts.get(index)?;
+        let end = self
+            .line_starts
+            .get(index + 1)
+            .map_or(self.text.len(), |&next| next - 1);
+        Some(self.text[start..end].trim_end_matches('\r'))
+    }
+}
+
+/// Every file loaded in a compilation, indexed by the `file_id` of a `Span`.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Add a file and return its id.
+    pub fn add(&mut self, name: String, text: String) -> usize {
+        self.files.push(SourceFile::new(name, text));
+        self.files.len() - 1
+    }
+
+    pub fn get(&self, file_id: usize) -> &SourceFile {
+        &self.files[file_id]
+    }
+
+    /// Every file, in the order they were added.
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// The positions of the start and end of a span.
+    pub fn range(&self, span: Span) -> (Position, Position) {
+        let file = self.get(span.file_id);
+        (file.position(span.start), file.position(span.end))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maps_offsets_to_lines_and_columns() {
+        let file = SourceFile::new("a.sk".to_string(), "ab\nöx\n\ny".to_string());
+        assert_eq!(file.position(0), Position { line: 1, col: 1 });
+        assert_eq!(file.position(2), Position { line: 1, col: 3 });
+        assert_eq!(file.position(3), Position { line: 2, col: 1 });
+        // `ö` is two bytes but one column.
+        assert_eq!(file.position(5), Position { line: 2, col: 2 });
+        assert_eq!(file.position(8), Position { line: 4, col: 1 });
+        assert_eq!(file.line(2), Some("öx"));
+        assert_eq!(file.line(3), Some(""));
+        assert_eq!(file.line(5), None);
+    }
+}
@@ -1,17 +1,70 @@
-use crate::types::{Keyword, Op, Position, Primitive, Token, TokenType};
-use std::iter::{self, from_fn};
+use crate::diagnostic::Diagnostic;
+use crate::types::{Keyword, Op, Position, Primitive, Span, Token, TokenType};
+use unicode_ident::{is_xid_continue, is_xid_start};
 
 pub struct Tokenizer {
-    file: String,
+    file_id: usize,
+}
+
+/// Walks the input one character at a time, keeping track of the byte offset and position.
+struct Cursor<'a> {
+    input: &'a str,
+    offset: usize,
+    pos: Position,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<char> {
+        self.peek_nth(0)
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.input[self.offset..].chars().nth(n)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.offset += ch.len_utf8();
+        if ch == '\n' {
+            self.pos.line += 1;
+            self.pos.col = 1;
+        } else {
+            self.pos.col += 1;
+        }
+        Some(ch)
+    }
+
+    /// Consume `expected` if it is the next character.
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consume characters while `predicate` holds and return them.
+    fn bump_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let start = self.offset;
+        while self.peek().is_some_and(&predicate) {
+            self.bump();
+        }
+        &self.input[start..self.offset]
+    }
 }
 
 impl Tokenizer {
-    pub fn new(file: String) -> Tokenizer {
-        Tokenizer { file }
+    pub fn new(file_id: usize) -> Tokenizer {
+        Tokenizer { file_id }
     }
 
-    pub fn print_tokens(&self, tokens: &Vec<Token>) {
+    /// Print one token per line with its position, leaving out whitespace.
+    pub fn print_tokens(&self, tokens: &[Token]) {
         for token in tokens {
+            if matches!(token.token_type, TokenType::Whitespace | TokenType::Newline) {
+                continue;
+            }
             println!(
                 "[{},{}] {:?}",
                 &token.pos.line, &token.pos.col, token.token_type
@@ -19,210 +72,625 @@ impl Tokenizer {
         }
     }
 
-    /// Produce a sequence of tokens from a given input string.
-    pub fn tokenize(&mut self, input: &str) -> Result<Vec<Token>, String> {
+    /// Produce a sequence of tokens from a given input string. Unexpected characters are
+    /// skipped so that all of them are reported.
+    pub fn tokenize(&mut self, input: &str) -> Result<Vec<Token>, Vec<Diagnostic>> {
         let mut tokens: Vec<Token> = Vec::new();
-        let mut iter = input.chars().peekable();
-        let mut pos = Position { line: 1, col: 1 };
-        let mut in_comment = false;
-        while let Some(ch) = iter.next() {
-            if in_comment {
-                if ch == '\n' {
-                    in_comment = false;
-                } else {
-                    pos.col += 1;
-                    continue;
-                }
-            }
+        let mut diagnostics = vec![];
+        let mut cursor = Cursor {
+            input,
+            offset: 0,
+            pos: Position { line: 1, col: 1 },
+        };
+        loop {
+            let start = cursor.offset;
+            let pos = cursor.pos.clone();
+            let Some(ch) = cursor.bump() else {
+                break;
+            };
 
-            match ch {
-                // Whitespace
-                ' ' | '\t' => {
-                    // multiple whitespaces are treated as one
-                    while let Some(&c) = iter.peek() {
-                        if c == ' ' || c == '\t' {
-                            pos.col += 1;
-                            iter.next();
-                        } else {
-                            break;
-                        }
-                    }
-                    tokens.push(Token::new(pos.clone(), TokenType::Whitespace));
-                }
-                // Newline
-                '\n' => {
-                    pos.line += 1;
-                    pos.col = 1;
-                    tokens.push(Token::new(pos.clone(), TokenType::Newline));
-                }
-                '0' => {
-                    tokens.push(Token::new(pos.clone(), TokenType::Number(0)));
+            let token_type = match ch {
+                // Whitespace, multiple whitespaces are treated as one
+                ' ' | '\t' | '\r' => {
+                    cursor.bump_while(|c| c == ' ' || c == '\t' || c == '\r');
+                    TokenType::Whitespace
                 }
+                '\n' => TokenType::Newline,
                 // Numbers
-                '1'..='9' => {
-                    let n: i64 = iter::once(ch)
-                        .chain(from_fn(|| {
-                            iter.by_ref().next_if(|s| {
-                                pos.col += 1;
-                                s.is_ascii_digit()
-                            })
-                        }))
-                        .collect::<String>()
-                        .parse()
-                        .expect("a digit");
-                    tokens.push(Token::new(pos.clone(), TokenType::Number(n)));
-                }
-                // Keywords
-                'a'..='z' | 'A'..='Z' => {
-                    let keyword: String = iter::once(ch)
-                        .chain(from_fn(|| {
-                            iter.by_ref().next_if(|s| {
-                                pos.col += 1;
-                                s.is_ascii_alphabetic() || s == &'_' || s.is_ascii_digit()
-                            })
-                        }))
-                        .collect();
-                    match keyword.as_str() {
-                        "let" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::Let)))
-                        }
-                        "return" => tokens
-                            .push(Token::new(pos.clone(), TokenType::Keyword(Keyword::Return))),
-                        "fn" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::Fn)))
-                        }
-                        "in" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::In)))
-                        }
-                        "is" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::Is)))
-                        }
-                        "of" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::Of)))
-                        }
-                        "while" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::While)))
-                        }
-                        "for" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::For)))
-                        }
-                        "int" => tokens.push(Token::new(
-                            pos.clone(),
-                            TokenType::Keyword(Keyword::Type(Primitive::Int)),
-                        )),
-                        "str" => tokens.push(Token::new(
-                            pos.clone(),
-                            TokenType::Keyword(Keyword::Type(Primitive::Str)),
-                        )),
-                        "double" => tokens.push(Token::new(
-                            pos.clone(),
-                            TokenType::Keyword(Keyword::Type(Primitive::Double)),
-                        )),
-                        "bool" => tokens.push(Token::new(
-                            pos.clone(),
-                            TokenType::Keyword(Keyword::Type(Primitive::Bool)),
-                        )),
-                        "void" => tokens.push(Token::new(
-                            pos.clone(),
-                            TokenType::Keyword(Keyword::Type(Primitive::Void)),
-                        )),
-                        "if" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::If)))
-                        }
-                        "else" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::Else)))
-                        }
-                        "struct" => tokens
-                            .push(Token::new(pos.clone(), TokenType::Keyword(Keyword::Struct))),
-                        "interface" => tokens.push(Token::new(
-                            pos.clone(),
-                            TokenType::Keyword(Keyword::Interface),
-                        )),
-                        "impl" => {
-                            tokens.push(Token::new(pos.clone(), TokenType::Keyword(Keyword::Impl)))
-                        }
-                        _ => tokens.push(Token::new(pos.clone(), TokenType::Identifier(keyword))),
+                '0'..='9' => match self.number(ch, &mut cursor, start) {
+                    Ok(token_type) => token_type,
+                    Err(diagnostic) => {
+                        diagnostics.push(*diagnostic);
+                        continue;
                     }
+                },
+                // Keywords and identifiers, following UAX #31 with a leading `_` allowed
+                c if c == '_' || is_xid_start(c) => {
+                    cursor.bump_while(is_xid_continue);
+                    Self::keyword(&input[start..cursor.offset])
                 }
                 // Operators
-                '+' => tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Add))),
+                '+' | '*' | '%' if cursor.peek() == Some('=') => {
+                    cursor.bump();
+                    TokenType::AssignOp(match ch {
+                        '+' => Op::Add,
+                        '*' => Op::Mul,
+                        _ => Op::Mod,
+                    })
+                }
+                '+' => TokenType::Operator(Op::Add),
                 '-' => {
-                    if let Some(&'>') = iter.peek() {
-                        iter.next();
-                        tokens.push(Token::new(pos.clone(), TokenType::RetArrow));
+                    if cursor.eat('>') {
+                        TokenType::RetArrow
+                    } else if cursor.eat('=') {
+                        TokenType::AssignOp(Op::Sub)
                     } else {
-                        tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Sub)))
+                        TokenType::Operator(Op::Sub)
                     }
                 }
-                '*' => tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Mul))),
-                '.' => tokens.push(Token::new(pos.clone(), TokenType::Dot)),
+                '*' => TokenType::Operator(Op::Mul),
+                '.' => TokenType::Dot,
                 '/' => {
-                    if let Some(&'/') = iter.peek() {
-                        in_comment = true;
-                        iter.next();
-                        tokens.push(Token::new(pos.clone(), TokenType::Comment));
+                    if cursor.eat('/') {
+                        let text = cursor.bump_while(|c| c != '\n');
+                        // `///` starts a doc comment, but `////` is an ordinary comment.
+                        match text.strip_prefix('/') {
+                            Some(doc) if !doc.starts_with('/') => TokenType::DocComment(
+                                doc.strip_prefix(' ').unwrap_or(doc).trim_end().to_string(),
+                            ),
+                            _ => TokenType::Comment,
+                        }
+                    } else if cursor.eat('*') {
+                        self.block_comment(&mut cursor, start, &mut diagnostics);
+                        TokenType::Comment
+                    } else if cursor.eat('=') {
+                        TokenType::AssignOp(Op::Div)
                     } else {
-                        tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Div)))
+                        TokenType::Operator(Op::Div)
+                    }
+                }
+                '%' => TokenType::Operator(Op::Mod),
+                '&' => {
+                    if cursor.eat('&') {
+                        TokenType::Operator(Op::And)
+                    } else {
+                        TokenType::Operator(Op::BitAnd)
+                    }
+                }
+                // A single `|` also delimits parameters, so the parser decides what it means.
+                '|' => {
+                    if cursor.eat('|') {
+                        TokenType::Operator(Op::Or)
+                    } else {
+                        TokenType::Pipe
+                    }
+                }
+                '^' => TokenType::Operator(Op::BitXor),
+                '?' => {
+                    if cursor.eat('?') {
+                        TokenType::Operator(Op::Coalesce)
+                    } else {
+                        TokenType::Question
+                    }
+                }
+                '=' => {
+                    if cursor.eat('=') {
+                        TokenType::Operator(Op::Eq)
+                    } else {
+                        TokenType::Assign
                     }
                 }
-                '%' => tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Mod))),
-                '&' => tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::And))),
-                '|' => tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Or))),
-                '=' => tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Eq))),
                 '!' => {
-                    if let Some(&'=') = iter.peek() {
-                        iter.next();
-                        tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Neq)));
+                    if cursor.eat('=') {
+                        TokenType::Operator(Op::Neq)
                     } else {
-                        tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Not)));
+                        TokenType::Operator(Op::Not)
                     }
                 }
                 '<' => {
-                    if let Some(&'=') = iter.peek() {
-                        iter.next();
-                        tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Lte)));
+                    if cursor.eat('=') {
+                        TokenType::Operator(Op::Lte)
+                    } else if cursor.eat('<') {
+                        TokenType::Operator(Op::Shl)
                     } else {
-                        tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Lt)));
+                        TokenType::Operator(Op::Lt)
                     }
                 }
                 '>' => {
-                    if let Some(&'=') = iter.peek() {
-                        iter.next();
-                        tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Gte)));
+                    if cursor.eat('=') {
+                        TokenType::Operator(Op::Gte)
+                    } else if cursor.eat('>') {
+                        TokenType::Operator(Op::Shr)
                     } else {
-                        tokens.push(Token::new(pos.clone(), TokenType::Operator(Op::Gt)));
+                        TokenType::Operator(Op::Gt)
                     }
                 }
-                // String literals
-                '"' => {
-                    let string: String = from_fn(|| {
-                        iter.by_ref().next_if(|s| {
-                            pos.col += 1;
-                            s != &'\"'
-                        })
-                    })
-                    .collect();
-                    tokens.push(Token::new(pos.clone(), TokenType::StrLiteral(string)));
-                    iter.next();
-                }
-                '(' => tokens.push(Token::new(pos.clone(), TokenType::LeftParen)),
-                ')' => tokens.push(Token::new(pos.clone(), TokenType::RightParen)),
-                '[' => tokens.push(Token::new(pos.clone(), TokenType::LeftBracket)),
-                ']' => tokens.push(Token::new(pos.clone(), TokenType::RightBracket)),
-                '{' => tokens.push(Token::new(pos.clone(), TokenType::LeftCurly)),
-                '}' => tokens.push(Token::new(pos.clone(), TokenType::RightCurly)),
-                ':' => tokens.push(Token::new(pos.clone(), TokenType::Colon)),
-                ';' => tokens.push(Token::new(pos.clone(), TokenType::Semicolon)),
-                ',' => tokens.push(Token::new(pos.clone(), TokenType::Comma)),
+                '"' => self.string(&mut cursor, start, &mut diagnostics),
+                '(' => TokenType::LeftParen,
+                ')' => TokenType::RightParen,
+                '[' => TokenType::LeftBracket,
+                ']' => TokenType::RightBracket,
+                '{' => TokenType::LeftCurly,
+                '}' => TokenType::RightCurly,
+                ':' => TokenType::Colon,
+                ';' => TokenType::Semicolon,
+                ',' => TokenType::Comma,
                 _ => {
-                    return Err(format!(
-                        "unexpected character at {}{}:{}: {}",
-                        self.file, &pos.line, pos.col, ch
-                    ));
+                    diagnostics.push(
+                        Diagnostic::error(
+                            self.span(start, cursor.offset),
+                            format!("unexpected character `{}`", ch),
+                        )
+                        .with_label("not valid here".to_string()),
+                    );
+                    continue;
                 }
-            }
+            };
+            tokens.push(Token::new(pos, self.span(start, cursor.offset), token_type));
         }
 
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
         Ok(tokens)
     }
+
+    /// Lex the rest of a number literal starting with `first`: a decimal integer, a float, or
+    /// a `0x`, `0o` or `0b` integer. Digits may be separated by `_`.
+    fn number(
+        &self,
+        first: char,
+        cursor: &mut Cursor,
+        start: usize,
+    ) -> Result<TokenType, Box<Diagnostic>> {
+        let radix = match (first, cursor.peek()) {
+            ('0', Some('x')) => 16,
+            ('0', Some('o')) => 8,
+            ('0', Some('b')) => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            cursor.bump();
+            // Take every alphanumeric character so that invalid digits are reported as such.
+            let digits = cursor.bump_while(|c| c.is_ascii_alphanumeric() || c == '_');
+            let span = self.span(start, cursor.offset);
+            let digits = digits.replace('_', "");
+            if digits.is_empty() {
+                let msg = "missing digits after the base prefix".to_string();
+                return Err(Box::new(Diagnostic::error(span, msg)));
+            }
+            if let Some(c) = digits.chars().find(|c| !c.is_digit(radix)) {
+                let msg = format!("invalid digit `{}` in a base {} literal", c, radix);
+                return Err(Box::new(Diagnostic::error(span, msg)));
+            }
+            return i64::from_str_radix(&digits, radix)
+                .map(TokenType::Number)
+                .map_err(|_| Self::out_of_range(span));
+        }
+
+        let is_digit = |c: char| c.is_ascii_digit() || c == '_';
+        cursor.bump_while(is_digit);
+        let mut is_float = false;
+        // `1.5` is a float, but `1.x` is a field access on `1`.
+        if cursor.peek() == Some('.') && cursor.peek_nth(1).is_some_and(|c| c.is_ascii_digit()) {
+            cursor.bump();
+            cursor.bump_while(is_digit);
+            is_float = true;
+        }
+        if matches!(cursor.peek(), Some('e' | 'E')) {
+            let digit = match cursor.peek_nth(1) {
+                Some('+' | '-') => 2,
+                _ => 1,
+            };
+            if cursor.peek_nth(digit).is_some_and(|c| c.is_ascii_digit()) {
+                for _ in 0..digit {
+                    cursor.bump();
+                }
+                cursor.bump_while(is_digit);
+                is_float = true;
+            }
+        }
+
+        let span = self.span(start, cursor.offset);
+        let text = cursor.input[start..cursor.offset].replace('_', "");
+        if is_float {
+            let n: f64 = text.parse().expect("a float");
+            if n.is_infinite() {
+                let msg = "float literal is out of range for `double`".to_string();
+                return Err(Box::new(Diagnostic::error(span, msg)));
+            }
+            Ok(TokenType::Float(n))
+        } else {
+            text.parse()
+                .map(TokenType::Number)
+                .map_err(|_| Self::out_of_range(span))
+        }
+    }
+
+    /// Lex the rest of a string literal, reporting bad escapes and a missing closing quote.
+    /// # Escapes:
+    /// `\n`, `\t`, `\\`, `\"` and `\u{1F600}`, a Unicode scalar value in hex.
+    fn string(
+        &self,
+        cursor: &mut Cursor,
+        start: usize,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> TokenType {
+        let mut string = String::new();
+        loop {
+            let escape_start = cursor.offset;
+            match cursor.bump() {
+                Some('"') => break,
+                Some('\\') => match cursor.bump() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('\\') => string.push('\\'),
+                    Some('"') => string.push('"'),
+                    Some('u') if cursor.eat('{') => {
+                        let digits = cursor.bump_while(|c| c.is_ascii_hexdigit());
+                        let closed = cursor.eat('}');
+                        let c = u32::from_str_radix(digits, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .filter(|_| closed && digits.len() <= 6);
+                        match c {
+                            Some(c) => string.push(c),
+                            None => diagnostics.push(
+                                Diagnostic::error(
+                                    self.span(escape_start, cursor.offset),
+                                    "invalid unicode escape".to_string(),
+                                )
+                                .with_note(
+                                    "expected 1 to 6 hex digits naming a character, e.g. `\\u{e9}`"
+                                        .to_string(),
+                                ),
+                            ),
+                        }
+                    }
+                    // Reported below as an unterminated string.
+                    None => {}
+                    Some(c) => diagnostics.push(Diagnostic::error(
+                        self.span(escape_start, cursor.offset),
+                        format!("unknown escape sequence `\\{}`", c),
+                    )),
+                },
+                Some(c) => string.push(c),
+                None => {
+                    diagnostics.push(
+                        Diagnostic::error(
+                            self.span(start, start + 1),
+                            "unterminated string literal".to_string(),
+                        )
+                        .with_label("string starts here".to_string()),
+                    );
+                    break;
+                }
+            }
+        }
+
+        TokenType::StrLiteral(string)
+    }
+
+    /// Skip the rest of a `/* ... */` comment. Block comments nest, so every `/*` inside needs
+    /// its own `*/`.
+    fn block_comment(&self, cursor: &mut Cursor, start: usize, diagnostics: &mut Vec<Diagnostic>) {
+        let mut depth = 1;
+        while depth > 0 {
+            match cursor.bump() {
+                Some('/') if cursor.eat('*') => depth += 1,
+                Some('*') if cursor.eat('/') => depth -= 1,
+                Some(_) => {}
+                None => {
+                    diagnostics.push(
+                        Diagnostic::error(
+                            self.span(start, start + 2),
+                            "unterminated block comment".to_string(),
+                        )
+                        .with_label("comment starts here".to_string()),
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    fn out_of_range(span: Span) -> Box<Diagnostic> {
+        let msg = "integer literal is out of range for `int`".to_string();
+        Box::new(
+            Diagnostic::error(span, msg).with_note(format!("the largest `int` is {}", i64::MAX)),
+        )
+    }
+
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span {
+            file_id: self.file_id,
+            start,
+            end,
+        }
+    }
+
+    /// The token for a keyword, or an identifier for any other word.
+    fn keyword(word: &str) -> TokenType {
+        let keyword = match word {
+            "let" => Keyword::Let,
+            "return" => Keyword::Return,
+            "fn" => Keyword::Fn,
+            "in" => Keyword::In,
+            "is" => Keyword::Is,
+            "of" => Keyword::Of,
+            "while" => Keyword::While,
+            "for" => Keyword::For,
+            "int" => Keyword::Type(Primitive::Int),
+            "str" => Keyword::Type(Primitive::Str),
+            "double" => Keyword::Type(Primitive::Double),
+            "bool" => Keyword::Type(Primitive::Bool),
+            "void" => Keyword::Type(Primitive::Void),
+            "if" => Keyword::If,
+            "else" => Keyword::Else,
+            "struct" => Keyword::Struct,
+            "interface" => Keyword::Interface,
+            "impl" => Keyword::Impl,
+            "true" => Keyword::True,
+            "false" => Keyword::False,
+            "none" => Keyword::None,
+            _ => return TokenType::Identifier(word.to_string()),
+        };
+        TokenType::Keyword(keyword)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reports_every_unexpected_character() {
+        let errors = Tokenizer::new(0)
+            .tokenize("let a = $;\nlet b = #;")
+            .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|d| d.message.clone()).collect();
+        assert_eq!(
+            messages,
+            vec!["unexpected character `$`", "unexpected character `#`"]
+        );
+        assert_eq!(errors[1].primary.span.start, 19);
+    }
+
+    #[test]
+    fn tracks_positions_and_spans() {
+        let tokens = Tokenizer::new(0)
+            .tokenize("let name = \"ab\";\n  foo(10) // hi\nx->y")
+            .unwrap();
+        let visible: Vec<(i64, i64, usize, usize)> = tokens
+            .iter()
+            .filter(|t| !matches!(t.token_type, TokenType::Whitespace | TokenType::Newline))
+            .map(|t| (t.pos.line, t.pos.col, t.span.start, t.span.end))
+            .collect();
+        assert_eq!(
+            visible,
+            vec![
+                (1, 1, 0, 3),
+                (1, 5, 4, 8),
+                (1, 10, 9, 10),
+                (1, 12, 11, 15),
+                (1, 16, 15, 16),
+                (2, 3, 19, 22),
+                (2, 6, 22, 23),
+                (2, 7, 23, 25),
+                (2, 9, 25, 26),
+                (2, 11, 27, 32),
+                (3, 1, 33, 34),
+                (3, 2, 34, 36),
+                (3, 4, 36, 37),
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let tokens = Tokenizer::new(0)
+            .tokenize("größe + 名前_2 + _x + Σx1 + éa")
+            .unwrap();
+        let identifiers: Vec<(String, i64)> = tokens
+            .iter()
+            .filter_map(|t| match &t.token_type {
+                TokenType::Identifier(name) => Some((name.clone(), t.pos.col)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            identifiers,
+            vec![
+                ("größe".to_string(), 1),
+                ("名前_2".to_string(), 9),
+                ("_x".to_string(), 16),
+                ("Σx1".to_string(), 21),
+                ("éa".to_string(), 27),
+            ]
+        );
+        // Symbols and digits cannot start an identifier.
+        assert!(Tokenizer::new(0).tokenize("let € = 1;").is_err());
+    }
+
+    #[test]
+    fn float_literals() {
+        let types = |source: &str| -> Vec<TokenType> {
+            Tokenizer::new(0)
+                .tokenize(source)
+                .unwrap()
+                .into_iter()
+                .map(|t| t.token_type)
+                .filter(|t| *t != TokenType::Whitespace)
+                .collect()
+        };
+        assert_eq!(
+            types("1.5 2e10 6.02e-23 0.25E+2 3"),
+            vec![
+                TokenType::Float(1.5),
+                TokenType::Float(2e10),
+                TokenType::Float(6.02e-23),
+                TokenType::Float(25.0),
+                TokenType::Number(3),
+            ]
+        );
+        // A dot or `e` not followed by digits is not part of the number.
+        assert_eq!(
+            types("1.x a.b 2.e"),
+            vec![
+                TokenType::Number(1),
+                TokenType::Dot,
+                TokenType::Identifier("x".to_string()),
+                TokenType::Identifier("a".to_string()),
+                TokenType::Dot,
+                TokenType::Identifier("b".to_string()),
+                TokenType::Number(2),
+                TokenType::Dot,
+                TokenType::Identifier("e".to_string()),
+            ]
+        );
+        assert!(Tokenizer::new(0).tokenize("1e999").is_err());
+    }
+
+    #[test]
+    fn integer_literals() {
+        let numbers = |source: &str| -> Vec<i64> {
+            Tokenizer::new(0)
+                .tokenize(source)
+                .unwrap()
+                .into_iter()
+                .filter_map(|t| match t.token_type {
+                    TokenType::Number(n) => Some(n),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(
+            numbers("007 0xFF 0o17 0b1010 1_000_000 0x_dead_beef 0"),
+            vec![7, 255, 15, 10, 1_000_000, 0xdead_beef, 0]
+        );
+        assert_eq!(numbers("9223372036854775807"), vec![i64::MAX]);
+
+        let error = |source: &str| -> String {
+            let errors = Tokenizer::new(0).tokenize(source).unwrap_err();
+            errors[0].message.clone()
+        };
+        assert_eq!(
+            error("9223372036854775808"),
+            "integer literal is out of range for `int`"
+        );
+        assert_eq!(
+            error("0x1_0000_0000_0000_0000"),
+            "integer literal is out of range for `int`"
+        );
+        assert_eq!(error("0b102"), "invalid digit `2` in a base 2 literal");
+        assert_eq!(error("0x"), "missing digits after the base prefix");
+    }
+
+    #[test]
+    fn string_escapes() {
+        let tokens = Tokenizer::new(0)
+            .tokenize("\"a\\n\\t\\\\\\\"\\u{e9}\\u{1F600}\" \"two\nlines\" x")
+            .unwrap();
+        assert_eq!(
+            tokens[0].token_type,
+            TokenType::StrLiteral("a\n\t\\\"é😀".to_string())
+        );
+        assert_eq!(
+            tokens[2].token_type,
+            TokenType::StrLiteral("two\nlines".to_string())
+        );
+        // The line break inside the string moves the following tokens down a line.
+        assert_eq!(tokens[4].pos, Position { line: 2, col: 8 });
+    }
+
+    #[test]
+    fn string_errors() {
+        let errors = Tokenizer::new(0)
+            .tokenize("let a = \"\\q \\u{110000} \\u{41\";\nlet b = \"open;\n")
+            .unwrap_err();
+        let messages: Vec<(&str, usize)> = errors
+            .iter()
+            .map(|d| (d.message.as_str(), d.primary.span.start))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                ("unknown escape sequence `\\q`", 9),
+                ("invalid unicode escape", 12),
+                ("invalid unicode escape", 23),
+                ("unterminated string literal", 39),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let types: Vec<TokenType> = Tokenizer::new(0)
+            .tokenize("a /* b /* c */ d */ e // f\n/// Doc  \n//// g\n///\nh")
+            .unwrap()
+            .into_iter()
+            .map(|t| t.token_type)
+            .filter(|t| !matches!(t, TokenType::Whitespace | TokenType::Newline))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Identifier("a".to_string()),
+                TokenType::Comment,
+                TokenType::Identifier("e".to_string()),
+                TokenType::Comment,
+                TokenType::DocComment("Doc".to_string()),
+                TokenType::Comment,
+                TokenType::DocComment("".to_string()),
+                TokenType::Identifier("h".to_string()),
+            ]
+        );
+
+        let errors = Tokenizer::new(0).tokenize("x /* a /* b */").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unterminated block comment");
+        assert_eq!(
+            errors[0].primary.span,
+            Span {
+                file_id: 0,
+                start: 2,
+                end: 4
+            }
+        );
+    }
+
+    #[test]
+    fn operators() {
+        let types: Vec<TokenType> = Tokenizer::new(0)
+            .tokenize("= == && || & | ^ << >> += -= *= /= %= <= -> ? ?? //")
+            .unwrap()
+            .into_iter()
+            .map(|t| t.token_type)
+            .filter(|t| *t != TokenType::Whitespace)
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Assign,
+                TokenType::Operator(Op::Eq),
+                TokenType::Operator(Op::And),
+                TokenType::Operator(Op::Or),
+                TokenType::Operator(Op::BitAnd),
+                TokenType::Pipe,
+                TokenType::Operator(Op::BitXor),
+                TokenType::Operator(Op::Shl),
+                TokenType::Operator(Op::Shr),
+                TokenType::AssignOp(Op::Add),
+                TokenType::AssignOp(Op::Sub),
+                TokenType::AssignOp(Op::Mul),
+                TokenType::AssignOp(Op::Div),
+                TokenType::AssignOp(Op::Mod),
+                TokenType::Operator(Op::Lte),
+                TokenType::RetArrow,
+                TokenType::Question,
+                TokenType::Operator(Op::Coalesce),
+                TokenType::Comment,
+            ]
+        );
+    }
 }
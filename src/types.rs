@@ -1,6 +1,6 @@
-use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct AST {
     pub definitions: Vec<Definition>,
 }
@@ -13,53 +13,271 @@ impl AST {
     }
 }
 
+/// A top level definition or a statement, which an input to the REPL mixes.
+#[derive(Debug, Clone)]
+pub enum Item {
+    Definition(Definition),
+    Statement(Statement),
+}
+
+/// A statement together with the source region it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+impl Statement {
+    pub fn new(kind: StatementKind, span: Span) -> Statement {
+        Statement { kind, span }
+    }
+}
+
+/// Spans are ignored so that trees can be compared structurally.
+impl PartialEq for Statement {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+/// A name or type as written in the source, such as the name of a parameter or the type
+/// annotation of a `let`, together with where it was written.
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Spanned<T> {
+        Spanned { node, span }
+    }
+}
+
+/// Spans are ignored so that trees can be compared structurally.
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+/// `name: type`, a parameter of a function or a field of a struct.
+pub type Param = (Spanned<String>, Spanned<Type>);
+
 #[derive(Debug, Clone, PartialEq)]
-pub enum Statement {
-    DeclStmt,
-    ForStmt,
-    ExprStmt,
-    AssignStmt,
-    IfStmt,
-    ElseStmt,
+pub enum StatementKind {
+    /// `let name[: type] = expr;`
+    DeclStmt(Spanned<String>, Option<Spanned<Type>>, Expression),
+    /// `for name in expr { ... }`
+    ForStmt(Spanned<String>, Expression, Box<Statement>),
+    /// `while cond { ... }`
+    WhileStmt(Expression, Box<Statement>),
+    ExprStmt(Expression),
+    /// `target = expr;`
+    AssignStmt(Expression, Expression),
+    /// `if cond { ... } [else { ... } | else if ...]`
+    IfStmt(Expression, Box<Statement>, Option<Box<Statement>>),
+    /// `if let name = expr { ... } [else ...]`, which runs the first branch with `name` bound
+    /// to the value of the optional `expr` unless it is `none`.
+    IfLetStmt(
+        Spanned<String>,
+        Expression,
+        Box<Statement>,
+        Option<Box<Statement>>,
+    ),
     Block(Box<Vec<Statement>>),
-    RetStmt(Expression),
+    RetStmt(Option<Expression>),
     EmptyStmt,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Definition {
-    FnDef(String, Vec<(String, Type)>, Option<Type>, Statement),
-    GlobalDef,
+    FnDef(Function),
+    /// `let name[: type] = expr;` at the top level, with its span and doc comment. Globals are
+    /// initialised in declaration order before `main` runs.
+    GlobalDef(
+        Spanned<String>,
+        Option<Spanned<Type>>,
+        Expression,
+        Span,
+        Option<String>,
+    ),
     StructDef(Struct),
 }
 
+impl Definition {
+    pub fn name(&self) -> &str {
+        match self {
+            Definition::FnDef(function) => &function.name,
+            Definition::GlobalDef(name, ..) => &name.node,
+            Definition::StructDef(s) => &s.name,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Definition::FnDef(function) => function.span,
+            Definition::GlobalDef(_, _, _, span, _) => *span,
+            Definition::StructDef(s) => s.span,
+        }
+    }
+
+    /// The text of the `///` comments written right before the definition, one line per
+    /// comment.
+    pub fn doc(&self) -> Option<&str> {
+        match self {
+            Definition::FnDef(function) => function.doc.as_deref(),
+            Definition::GlobalDef(.., doc) => doc.as_deref(),
+            Definition::StructDef(s) => s.doc.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub return_type: Option<Spanned<Type>>,
+    pub body: Statement,
+    pub span: Span,
+    pub name_span: Span,
+    pub doc: Option<String>,
+}
+
+impl Function {
+    /// The type of the function as a value, `|params|: return type`.
+    pub fn value_type(&self) -> Type {
+        Type::Function(
+            self.params.iter().map(|(_, t)| t.node.clone()).collect(),
+            Box::new(
+                self.return_type
+                    .as_ref()
+                    .map_or(Type::Void, |t| t.node.clone()),
+            ),
+        )
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type {
-    Int(Option<i64>),
-    Double(Option<f64>),
-    String(Option<String>),
-    Bool(Option<bool>),
+    Int,
+    Double,
+    String,
+    Bool,
     Void,
     Struct(String),
+    /// `|params|: return type`
+    Function(Vec<Type>, Box<Type>),
+    /// `T?`, a `T` or `none`. A plain `T` is never `none`.
+    Optional(Box<Type>),
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Double => write!(f, "double"),
+            Type::String => write!(f, "str"),
+            Type::Bool => write!(f, "bool"),
+            Type::Void => write!(f, "void"),
+            Type::Struct(name) => write!(f, "{}", name),
+            Type::Function(params, return_type) => {
+                let params: Vec<String> = params.iter().map(|t| t.to_string()).collect();
+                write!(f, "|{}|: {}", params.join(", "), return_type)
+            }
+            Type::Optional(t) => match **t {
+                Type::Function(..) => write!(f, "({})?", t),
+                _ => write!(f, "{}?", t),
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Struct {
-    name: String,
-    fields: HashMap<String, Box<Type>>,
+    pub name: String,
+    /// Fields in declaration order.
+    pub fields: Vec<Param>,
+    pub span: Span,
+    pub name_span: Span,
+    pub doc: Option<String>,
+}
+
+impl Struct {
+    /// The type of the field `name`.
+    pub fn field(&self, name: &str) -> Option<&Type> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.node == name)
+            .map(|(_, t)| &t.node)
+    }
+
+    /// Whether the struct declares exactly the fields `names`, in any order.
+    pub fn has_fields(&self, names: &[&str]) -> bool {
+        self.fields.len() == names.len()
+            && self
+                .fields
+                .iter()
+                .all(|(f, _)| names.contains(&f.node.as_str()))
+    }
+}
+
+/// An anonymous function, `|x: int|: int { return x * factor; }`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnonFn {
+    pub params: Vec<Param>,
+    /// `None` for a `void` function written without a return type.
+    pub return_type: Option<Spanned<Type>>,
+    pub body: Box<Statement>,
+    /// Locals of enclosing functions used by the body, filled in by `captures::analyze`.
+    pub captures: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Int(i64),
+    Double(f64),
+    String(String),
+    Bool(bool),
+    /// `none`, the missing value of an optional type.
+    None,
+}
+
+/// An expression together with the source region it was parsed from.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Expression {
+        Expression { kind, span }
+    }
+}
+
+/// Spans are ignored so that trees can be compared structurally.
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Expression {
-    VarDecl(String),
-    VarDeclInit(String),
+pub enum ExpressionKind {
     VarAccess(String),
-    AnonFnDef(HashMap<String, Type>),
-    FnCall(String),
-    VarAssign(Box<Expression>, Box<Expression>),
+    AnonFnDef(AnonFn),
+    /// `callee(args)`. The callee is usually the name of a function, but may be any
+    /// expression that evaluates to one.
+    FnCall(Box<Expression>, Vec<Expression>),
     BinaryOp(Op, Box<Expression>, Box<Expression>),
-    UnaryOp(Box<Expression>),
-    Literal(Type),
+    UnaryOp(Op, Box<Expression>),
+    Literal(Literal),
+    /// `Name { field: expr, ... }`, or `{ field = expr, ... }` when the struct is inferred from
+    /// context. Fields are kept in the order they were written.
+    StructLiteral(Option<String>, Vec<(String, Expression)>),
+    /// `expr.field`
+    FieldAccess(Box<Expression>, String),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -77,6 +295,9 @@ pub enum Keyword {
     Impl,
     Struct,
     Interface,
+    True,
+    False,
+    None,
     Type(Primitive),
     Any,
 }
@@ -97,12 +318,20 @@ pub enum Op {
     Gt,
     Lte,
     Gte,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    /// `??`
+    Coalesce,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
     Identifier(String),
     Number(i64),
+    Float(f64),
     StrLiteral(String),
     Operator(Op),
     Keyword(Keyword),
@@ -117,10 +346,18 @@ pub enum TokenType {
     Colon,
     Comma,
     Dot,
+    /// `?`, which makes a type optional
+    Question,
     Whitespace,
     Comment,
+    /// `/// text`, documenting the definition that follows it
+    DocComment(String),
     Newline,
     RetArrow,
+    /// `=`
+    Assign,
+    /// `+=`, `-=`, `*=`, `/=` or `%=`
+    AssignOp(Op),
     EOF,
 }
 
@@ -128,14 +365,42 @@ pub enum TokenType {
 pub struct Token {
     pub token_type: TokenType,
     pub pos: Position,
+    pub span: Span,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// A line and column, both starting at 1. Columns count characters, not bytes.
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Position {
     pub line: i64,
     pub col: i64,
 }
 
+/// A byte range `start..end` in the file with the given id, see `source::SourceMap`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub file_id: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Spans are printed as `file_id:start..end` to keep printed trees readable.
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}..{}", self.file_id, self.start, self.end)
+    }
+}
+
+impl Span {
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            file_id: self.file_id,
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Primitive {
     Int,
@@ -146,7 +411,11 @@ pub enum Primitive {
 }
 
 impl Token {
-    pub fn new(pos: Position, token_type: TokenType) -> Token {
-        Token { token_type, pos }
+    pub fn new(pos: Position, span: Span, token_type: TokenType) -> Token {
+        Token {
+            token_type,
+            pos,
+            span,
+        }
     }
 }